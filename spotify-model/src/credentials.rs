@@ -0,0 +1,350 @@
+use error::*;
+use spotify;
+use rpassword;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Somewhere a secret, such as the app's credentials, can be read from
+pub trait CredentialProvider {
+    /// For logging which provider was tried
+    fn describe(&self) -> String;
+
+    /// `Ok(None)` means this provider has nothing to offer, so the next should be tried
+    fn fetch(&self) -> SpotifyResult<Option<String>>;
+}
+
+/// Where each kind of provider looks for a particular secret
+pub struct Secret {
+    pub env_var: &'static str,
+    pub file_name: &'static str,
+    pub command_env_var: &'static str,
+    pub prompt: &'static str,
+    /// Names the env var holding the order the providers are tried in
+    pub providers_env_var: &'static str,
+    /// The order when that isn't set
+    pub default_providers: &'static str,
+}
+
+/// The client id, optionally followed by `:client_secret`
+pub const CLIENT_CREDENTIALS: Secret = Secret {
+    env_var: "SPOTIFY_CLIENT_CREDS",
+    file_name: "credentials",
+    command_env_var: "SPOTIFY_CLIENT_CREDS_COMMAND",
+    prompt: "Spotify client id[:secret]: ",
+    providers_env_var: "SPOTIFY_CREDS_PROVIDERS",
+    default_providers: "env,file,command",
+};
+
+/// Held `user:password` before authorising moved to PKCE. It's never read, so that a password
+/// left in it can't be sent to the accounts server as a client secret
+pub const LEGACY_CREDS_ENV: &str = "SPOTIFY_CREDS";
+
+pub struct EnvProvider {
+    var: String,
+}
+
+pub struct FileProvider {
+    path: PathBuf,
+}
+
+pub struct PromptProvider {
+    prompt: String,
+}
+
+/// Runs an external command such as `pass show spotify`, taking the first line of its output
+pub struct CommandProvider {
+    program: String,
+    args: Vec<String>,
+}
+
+pub struct ProviderChain {
+    providers: Vec<Box<dyn CredentialProvider>>,
+}
+
+fn non_empty(s: &str) -> Option<String> {
+    let s = s.trim();
+    if s.is_empty() {
+        None
+    } else {
+        Some(s.to_owned())
+    }
+}
+
+impl EnvProvider {
+    pub fn new(var: &str) -> Self {
+        EnvProvider { var: var.to_owned() }
+    }
+}
+
+impl CredentialProvider for EnvProvider {
+    fn describe(&self) -> String {
+        format!("env var {}", self.var)
+    }
+
+    fn fetch(&self) -> SpotifyResult<Option<String>> {
+        Ok(env::var(&self.var).ok().and_then(|s| non_empty(&s)))
+    }
+}
+
+impl FileProvider {
+    pub fn new(path: PathBuf) -> Self {
+        FileProvider { path: path }
+    }
+}
+
+impl CredentialProvider for FileProvider {
+    fn describe(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+
+    fn fetch(&self) -> SpotifyResult<Option<String>> {
+        let mut f = match File::open(&self.path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)?;
+        Ok(contents.lines().next().and_then(non_empty))
+    }
+}
+
+impl PromptProvider {
+    pub fn new(prompt: &str) -> Self {
+        PromptProvider { prompt: prompt.to_owned() }
+    }
+}
+
+impl CredentialProvider for PromptProvider {
+    fn describe(&self) -> String {
+        String::from("terminal prompt")
+    }
+
+    fn fetch(&self) -> SpotifyResult<Option<String>> {
+        let input = rpassword::prompt_password_stderr(&self.prompt)?;
+        Ok(non_empty(&input))
+    }
+}
+
+impl CommandProvider {
+    /// Splits `command` on whitespace, or returns `None` if it is empty
+    pub fn parse(command: &str) -> Option<Self> {
+        let mut words = command.split_whitespace().map(|s| s.to_owned());
+        words.next().map(|program| {
+            CommandProvider {
+                program: program,
+                args: words.collect(),
+            }
+        })
+    }
+
+    fn command_line(&self) -> String {
+        let mut s = self.program.clone();
+        for arg in &self.args {
+            s.push(' ');
+            s.push_str(arg);
+        }
+        s
+    }
+}
+
+impl CredentialProvider for CommandProvider {
+    fn describe(&self) -> String {
+        format!("command '{}'", self.command_line())
+    }
+
+    fn fetch(&self) -> SpotifyResult<Option<String>> {
+        let output = Command::new(&self.program)
+            .args(&self.args)
+            .output()
+            .chain_err(|| {
+                ErrorKind::CredentialCommandFailed(self.command_line(), None)
+            })?;
+
+        if !output.status.success() {
+            bail!(ErrorKind::CredentialCommandFailed(
+                self.command_line(),
+                output.status.code(),
+            ));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        Ok(stdout.lines().next().and_then(non_empty))
+    }
+}
+
+impl ProviderChain {
+    pub fn new() -> Self {
+        ProviderChain { providers: Vec::new() }
+    }
+
+    pub fn with(mut self, provider: Box<dyn CredentialProvider>) -> Self {
+        self.providers.push(provider);
+        self
+    }
+
+    /// Builds a chain from comma-separated provider names: `env`, `file`, `command` and `prompt`.
+    /// `command` is skipped if the secret's command env var isn't set
+    pub fn from_order(order: &str, secret: &Secret) -> SpotifyResult<Self> {
+        let mut chain = ProviderChain::new();
+        for name in order.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            chain = match name {
+                "env" => chain.with(Box::new(EnvProvider::new(secret.env_var))),
+                "file" => {
+                    let mut path = spotify::config_dir();
+                    path.push(secret.file_name);
+                    chain.with(Box::new(FileProvider::new(path)))
+                }
+                "prompt" => chain.with(Box::new(PromptProvider::new(secret.prompt))),
+                "command" => {
+                    match env::var(secret.command_env_var).ok().and_then(
+                        |c| CommandProvider::parse(&c),
+                    ) {
+                        Some(p) => chain.with(Box::new(p)),
+                        None => chain,
+                    }
+                }
+                _ => bail!(ErrorKind::UnknownCredentialProvider(name.to_owned())),
+            };
+        }
+        Ok(chain)
+    }
+
+    /// The order comes from the secret's providers env var, such as `SPOTIFY_CREDS_PROVIDERS`,
+    /// with comma-separated provider names
+    pub fn from_env(secret: &Secret) -> SpotifyResult<Self> {
+        let order = env::var(secret.providers_env_var)
+            .unwrap_or_else(|_| secret.default_providers.to_owned());
+        ProviderChain::from_order(&order, secret)
+    }
+
+    /// Returns the secret from the first provider that has one. If none do, the error from
+    /// the last provider that failed is returned, so that a broken provider isn't mistaken for
+    /// having nothing to offer
+    pub fn fetch(&self) -> SpotifyResult<String> {
+        let mut last_err = None;
+        for provider in &self.providers {
+            match provider.fetch() {
+                Ok(Some(secret)) => {
+                    debug!("Read credentials from {}", provider.describe());
+                    return Ok(secret);
+                }
+                Ok(None) => trace!("No credentials in {}", provider.describe()),
+                Err(e) => {
+                    warn!("Failed to read credentials from {}: {}", provider.describe(), e);
+                    last_err = Some((provider.describe(), e));
+                }
+            }
+        }
+
+        match last_err {
+            Some((provider, e)) => {
+                Err(e).chain_err(|| format!("Failed to read credentials from {}", provider))
+            }
+            None => {
+                let tried = self.providers
+                    .iter()
+                    .map(|p| p.describe())
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                Err(ErrorKind::NoCredentials(tried).into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use credentials::*;
+    use http::cachecrypt::CACHE_PASSPHRASE;
+    use std::fs;
+    use std::io::Write;
+
+    struct Fixed(Option<&'static str>);
+
+    impl CredentialProvider for Fixed {
+        fn describe(&self) -> String {
+            format!("fixed {:?}", self.0)
+        }
+
+        fn fetch(&self) -> SpotifyResult<Option<String>> {
+            Ok(self.0.map(|s| s.to_owned()))
+        }
+    }
+
+    #[test]
+    fn env_provider() {
+        env::set_var("SPOTIFY_MODEL_TEST_CREDS", "  id:secret\n");
+        assert_eq!(
+            EnvProvider::new("SPOTIFY_MODEL_TEST_CREDS").fetch().unwrap(),
+            Some(String::from("id:secret"))
+        );
+        assert_eq!(EnvProvider::new("SPOTIFY_MODEL_TEST_NOPE").fetch().unwrap(), None);
+    }
+
+    #[test]
+    fn file_provider() {
+        let path = env::temp_dir().join("spotify-model-test-creds");
+        File::create(&path).unwrap().write_all(b"id:secret\nignored\n").unwrap();
+        assert_eq!(
+            FileProvider::new(path.clone()).fetch().unwrap(),
+            Some(String::from("id:secret"))
+        );
+        fs::remove_file(&path).unwrap();
+        assert_eq!(FileProvider::new(path).fetch().unwrap(), None);
+    }
+
+    #[test]
+    fn command_provider() {
+        let cmd = CommandProvider::parse("echo  id:secret").unwrap();
+        assert_eq!(cmd.describe(), "command 'echo id:secret'");
+        assert_eq!(cmd.fetch().unwrap(), Some(String::from("id:secret")));
+
+        match CommandProvider::parse("false").unwrap().fetch() {
+            Err(Error(ErrorKind::CredentialCommandFailed(_, Some(1)), _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+        assert!(CommandProvider::parse("  ").is_none());
+    }
+
+    #[test]
+    fn chain_order() {
+        let chain = ProviderChain::new()
+            .with(Box::new(Fixed(None)))
+            .with(Box::new(CommandProvider::parse("false").unwrap()))
+            .with(Box::new(Fixed(Some("second"))))
+            .with(Box::new(Fixed(Some("third"))));
+        assert_eq!(chain.fetch().unwrap(), "second");
+
+        let chain = ProviderChain::new()
+            .with(Box::new(CommandProvider::parse("false").unwrap()))
+            .with(Box::new(Fixed(None)));
+        match chain.fetch() {
+            Err(Error(ErrorKind::Msg(_), _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+
+        match ProviderChain::new().with(Box::new(Fixed(None))).fetch() {
+            Err(Error(ErrorKind::NoCredentials(ref tried), _)) => assert_eq!(tried, "fixed None"),
+            _ => assert!(false, "Error not returned"),
+        }
+
+        match ProviderChain::from_order("env,carrier-pigeon", &CLIENT_CREDENTIALS) {
+            Err(Error(ErrorKind::UnknownCredentialProvider(ref p), _)) => {
+                assert_eq!(p, "carrier-pigeon")
+            }
+            _ => assert!(false, "Error not returned"),
+        }
+        assert_eq!(ProviderChain::from_order("", &CLIENT_CREDENTIALS).unwrap().providers.len(), 0);
+
+        // a passphrase isn't read from a file next to the cache it protects, unless asked to be
+        let chain = ProviderChain::from_env(&CACHE_PASSPHRASE).unwrap();
+        let names: Vec<_> = chain.providers.iter().map(|p| p.describe()).collect();
+        assert_eq!(names, ["env var SPOTIFY_CACHE_PASSPHRASE", "terminal prompt"]);
+    }
+}
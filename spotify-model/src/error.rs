@@ -3,6 +3,8 @@ use reqwest;
 use url;
 use log;
 
+use http::scope::Scopes;
+
 error_chain! {
 
     types {
@@ -17,28 +19,175 @@ error_chain! {
     }
 
     errors {
-        AuthMissingCookie(cookie: String) {
-            display("Expected cookie '{}' was not present", cookie)
+        NoCredentials(tried: String) {
+            display("no credentials found, tried: {}", tried)
+        }
+
+        UnknownCredentialProvider(name: String) {
+            display("unknown credential provider '{}'", name)
+        }
+
+        CredentialCommandFailed(command: String, code: Option<i32>) {
+            display("credential command '{}' failed (exit code {:?})", command, code)
+        }
+
+        BadCredentials(reason: &'static str) {
+            display("malformed credentials: {}", reason)
+        }
+
+        AuthDeclined {
+            display("authorisation was declined on the consent page")
+        }
+
+        AuthDenied(reason: String) {
+            display("authorisation was not granted: {}", reason)
+        }
+
+        AuthStateMismatch {
+            display("authorisation redirect did not match our request")
+        }
+
+        AuthInvalidClient(description: String) {
+            display("the client id or secret was rejected: {}", description)
+        }
+
+        AuthInvalidGrant(description: String) {
+            display("the authorisation code or refresh token was rejected: {}", description)
+        }
+
+        AuthRateLimited(retry_after: Option<u64>) {
+            display("rate limited by the accounts server{}", match *retry_after {
+                Some(secs) => format!(", retry after {}s", secs),
+                None => String::new(),
+            })
+        }
+
+        AuthChallenge {
+            display("the accounts server responded with a web page, such as a captcha or challenge")
+        }
+
+        AuthNetwork {
+            display("failed to reach the accounts server")
+        }
+
+        AuthTimedOut(secs: u64) {
+            display("no authorisation redirect arrived within {}s", secs)
+        }
+
+        AuthTokenRequestFailed(code: reqwest::StatusCode, error: String) {
+            display("token request was rejected ({:?}): {}", code, error)
+        }
+
+        AuthBadResponse(reason: &'static str) {
+            display("unexpected response from accounts server: {}", reason)
         }
 
-        AuthBadCreds {
-            display("bad credentials")
+        AuthMissingClientSecret {
+            display("app-only authorisation needs a client secret")
         }
 
-        AuthFailedAccept {
-            display("spotify rejected the authorisation request")
+        UserRequired(endpoint: &'static str) {
+            display("{} needs a user, but we are authorised as an app only", endpoint)
         }
 
-        BadTokenCache(reason: &'static str) {
+        BadEndpointUse(endpoint: &'static str, reason: String) {
+            display("can't use endpoint {}: {}", endpoint, reason)
+        }
+
+        BadTokenCache(reason: String) {
             display("token cache invalid: {}", reason)
         }
 
+        TokenCacheEncrypted {
+            display("token cache is encrypted, but no passphrase or key file was given")
+        }
+
+        TokenCacheDecryptFailed {
+            display("failed to decrypt token cache, the passphrase or key file may be wrong")
+        }
+
+        ProfileNotFound(name: String) {
+            display("no profile named '{}'", name)
+        }
+
+        ProfileExists(name: String) {
+            display("a profile named '{}' already exists", name)
+        }
+
+        ProfileNotRemovable(name: String, reason: &'static str) {
+            display("can't remove profile '{}', {}", name, reason)
+        }
+
+        BadProfileName(name: String) {
+            display("invalid profile name '{}', only letters, digits, '-' and '_' are allowed", name)
+        }
+
+        BadProfileSettings(name: String) {
+            display("settings for profile '{}' are invalid", name)
+        }
+
         BadResponseStatusCode(code: reqwest::StatusCode) {
             display("unexpected response status code ({:?})", code)
         }
 
+        BadResponse(reason: &'static str) {
+            display("unexpected response from the API: {}", reason)
+        }
+
+        RateLimited(retry_after: Option<u64>) {
+            display("rate limited by the API{}", match *retry_after {
+                Some(secs) => format!(", retry after {}s", secs),
+                None => String::new(),
+            })
+        }
+
+        PartialResult(what: &'static str, fetched: usize, expected: usize) {
+            display("only fetched {} of {} {} before failing", fetched, expected, what)
+        }
+
+        Unauthorized {
+            display("request was unauthorised, even with a renewed token")
+        }
+
+        Forbidden(message: String, required: Scopes, granted: Scopes) {
+            display("request was forbidden: {} (needs scopes '{}', the token has '{}')",
+                    message, required, granted)
+        }
+
         NotImplemented {
             display("not implemented")
         }
     }
 }
+
+impl ErrorKind {
+    /// A suggestion for the user on how to resolve the error, if there's anything they can do
+    pub fn hint(&self) -> Option<&'static str> {
+        Some(match *self {
+            ErrorKind::AuthDeclined => "run again and accept the permissions on the consent page",
+            ErrorKind::AuthInvalidClient(_) => {
+                "check the client id and secret in SPOTIFY_CLIENT_CREDS or the credentials file"
+            }
+            ErrorKind::AuthInvalidGrant(_) => {
+                "the grant has expired or been revoked, run `logout` then authorise again"
+            }
+            ErrorKind::AuthRateLimited(_) |
+            ErrorKind::RateLimited(_) => "wait a while before trying again",
+            ErrorKind::AuthChallenge => {
+                "a proxy or the accounts server wants a browser, try SPOTIFY_AUTH_FLOW=manual"
+            }
+            ErrorKind::AuthNetwork => "check your internet connection and any proxy settings",
+            ErrorKind::AuthTimedOut(_) => {
+                "run again and finish authorising in the browser, or try SPOTIFY_AUTH_FLOW=manual"
+            }
+            ErrorKind::AuthStateMismatch => "authorise again, and only follow the latest link",
+            ErrorKind::AuthBadResponse(_) => {
+                "the accounts server may have changed, check for an update to this client"
+            }
+            ErrorKind::TokenCacheDecryptFailed => {
+                "check SPOTIFY_CACHE_PASSPHRASE or the key file, or `logout` to start afresh"
+            }
+            _ => return None,
+        })
+    }
+}
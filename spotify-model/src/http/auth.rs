@@ -1,71 +1,350 @@
-use time;
+use time::{self, Duration};
 
-use url;
+use rand::{self, Rng};
+use sha2::{Sha256, Digest};
+use base64;
+use json::{self, JsonValue};
 use error::*;
-use reqwest::*;
-use reqwest::header::*;
+use reqwest::{Client, RedirectPolicy, Response, StatusCode, Url};
+use reqwest::header::{Authorization, Bearer};
+use http::scope::{Scope, Scopes};
+use http::filecache;
+use http::cachecrypt::CacheEncryption;
+use http::responsecache::ResponseCache;
+use http::ratelimit::RateLimiter;
+use http::retry::RetryPolicy;
+use http::events::{AuthEvent, AuthObserver, Observers, ReauthReason};
+use http::endpoints::Endpoints;
+use http::request::retry_after;
+use http::params::{Params, RedirectParams};
+use redact::{redacted, Redacted};
 
-use std::cell::RefCell;
+use std::sync::{Mutex, RwLock};
+use std::fmt;
+use std::env;
+use std::io::{self, Read, Write, BufRead, BufReader};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration as StdDuration, Instant};
 
-#[derive(Debug)]
+#[derive(Clone)]
 pub struct AuthState {
     pub token: String,
+    pub token_type: String,
     pub expiry_time: i64,
+    pub refresh_token: Option<String>,
+    pub scopes: Scopes,
+    /// The id of the user the token was issued for, if known
+    pub account: Option<String>,
 }
 
-#[derive(Debug)]
 pub struct Creds {
-    username: String,
-    password: String,
+    client_id: String,
+    client_secret: Option<String>,
+}
+
+impl fmt::Debug for AuthState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AuthState")
+            .field("token", &Redacted)
+            .field("token_type", &self.token_type)
+            .field("expiry_time", &self.expiry_time)
+            .field("refresh_token", &redacted(&self.refresh_token))
+            .field("scopes", &self.scopes)
+            .field("account", &self.account)
+            .finish()
+    }
+}
+
+impl fmt::Debug for Creds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Creds")
+            .field("client_id", &self.client_id)
+            .field("client_secret", &redacted(&self.client_secret))
+            .finish()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AuthMode {
+    /// On behalf of a user, who authorises us in their browser
+    User,
+    /// As the application alone, without access to anything user-scoped
+    App,
+}
+
+/// How the user's authorisation gets back to us
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AuthFlow {
+    /// Open a browser, and catch its redirect on a localhost port
+    Loopback,
+    /// Print the authorisation URL and read the redirected URL pasted back in, for when there's
+    /// no browser or reachable localhost, such as over SSH
+    Manual,
 }
 
 #[derive(Debug)]
 pub struct Auth {
     client: Client,
-    pub state: RefCell<Option<AuthState>>,
+    pub state: RwLock<Option<AuthState>>,
     pub creds: Creds,
+    mode: AuthMode,
+    flow: AuthFlow,
+    /// Grows as operations need more than was originally asked for
+    scopes: Mutex<Scopes>,
+    /// Held by the one thread renewing the token, while the others wait for its result
+    renewal: Mutex<()>,
+    /// How long before expiry a token is renewed, so it doesn't expire partway through a request
+    renewal_margin: Duration,
+    cache_path: PathBuf,
+    cache_encryption: Option<CacheEncryption>,
+    endpoints: Endpoints,
+    redirect_port: u16,
+    observers: Observers,
+    /// API responses kept for conditional requests, if enabled
+    response_cache: Option<ResponseCache>,
+    /// Shared by every API request made with this auth
+    rate_limiter: RateLimiter,
+    /// Which failed API requests are tried again
+    retry_policy: RetryPolicy,
+}
+
+/// Selects the auth flow: unset or `loopback`, or `manual`
+pub const FLOW_ENV: &str = "SPOTIFY_AUTH_FLOW";
+
+const SPOTIFY_CLIENT_ID: &str = "a4a869822602493c828f424d7552379c";
+const REDIRECT_PORT: u16 = 8888;
+const DEFAULT_SCOPES: &[Scope] = &[Scope::UserLibraryRead];
+const RENEWAL_MARGIN_SECS: i64 = 60;
+/// How long the user has to finish authorising in the browser
+const REDIRECT_TIMEOUT_SECS: u64 = 300;
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng().gen_ascii_chars().take(len).collect()
 }
 
-const CSRF: &str = "csrf_token";
+/// Proof Key for Code Exchange, as described in RFC 7636
+struct Pkce {
+    verifier: String,
+    challenge: String,
+}
 
-fn extract_from_flattened_list<'a>(src: &'a str, key: &str, sep: char) -> Option<&'a str> {
-    if let Some(start) = src.find(key) {
-        let start = start + key.len() + 1; // +1 for =
-        if start >= src.len() {
-            return None;
+impl Pkce {
+    fn new() -> Self {
+        // alphanumerics are all in the allowed set of unreserved characters
+        Pkce::from_verifier(random_string(64))
+    }
+
+    fn from_verifier(verifier: String) -> Self {
+        let hash = Sha256::digest(verifier.as_bytes());
+        Pkce {
+            challenge: base64::encode_config(&hash, base64::URL_SAFE_NO_PAD),
+            verifier: verifier,
         }
+    }
+}
 
-        let end = match src[start..].find(sep) {
-            Some(i) => i + start,
-            None => src.len(),
-        };
-        return Some(&src[start..end]);
+/// Pulls the authorisation code out of a redirect's query string
+fn code_from_query(query: &Params, csrf_state: &str) -> SpotifyResult<String> {
+    match query.get("error") {
+        Some("access_denied") => bail!(ErrorKind::AuthDeclined),
+        Some(e) => bail!(ErrorKind::AuthDenied(e.to_owned())),
+        None => (),
     }
 
-    None
+    if query.get("state") != Some(csrf_state) {
+        bail!(ErrorKind::AuthStateMismatch);
+    }
+
+    query
+        .get("code")
+        .map(|c| c.to_owned())
+        .ok_or_else(|| ErrorKind::AuthBadResponse("missing code in redirect").into())
 }
 
-fn extract_cookie_value<'a>(headers: &'a Headers, key: &str) -> SpotifyResult<&'a str> {
-    headers
-        .get::<SetCookie>()
-        .and_then(|&SetCookie(ref values)| {
-            values.iter().find(|&x| x.starts_with(key)).and_then(|c| {
-                extract_from_flattened_list(c, key, ';')
-            })
+/// Turns an unsuccessful token response into the most specific error we can
+fn diagnose_token_response(response: &Response, raw: &str) -> SpotifyResult<()> {
+    let status = *response.status();
+
+    // json is expected whatever the status, so a page is something meant for a browser
+    if raw.trim_start().starts_with('<') {
+        bail!(ErrorKind::AuthChallenge);
+    }
+
+    if status == StatusCode::TooManyRequests {
+        bail!(ErrorKind::AuthRateLimited(retry_after(response)));
+    }
+
+    if status.is_success() {
+        return Ok(());
+    }
+
+    // as described in RFC 6749 section 5.2
+    let body = json::parse(raw).unwrap_or(JsonValue::Null);
+    let error = body["error"].as_str().unwrap_or("").to_owned();
+    let description = body["error_description"]
+        .as_str()
+        .unwrap_or(&error)
+        .to_owned();
+
+    Err(match error.as_str() {
+        "invalid_client" => ErrorKind::AuthInvalidClient(description),
+        "invalid_grant" => ErrorKind::AuthInvalidGrant(description),
+        _ if status == StatusCode::Unauthorized => ErrorKind::AuthInvalidClient(description),
+        _ => ErrorKind::AuthTokenRequestFailed(status, error),
+    }.into())
+}
+
+/// Only the query is looked at, as the authorisation page is only ever asked for a code
+fn parse_pasted_redirect(pasted: &str, csrf_state: &str) -> SpotifyResult<String> {
+    code_from_query(&RedirectParams::parse(pasted).query, csrf_state)
+}
+
+/// Catches the redirect back from the authorisation page on a localhost port
+struct LoopbackListener {
+    listener: TcpListener,
+    redirect_uri: String,
+    timeout: StdDuration,
+}
+
+impl LoopbackListener {
+    const PATH: &'static str = "/callback";
+
+    fn bind(port: u16) -> SpotifyResult<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let redirect_uri = format!(
+            "http://127.0.0.1:{}{}",
+            listener.local_addr()?.port(),
+            LoopbackListener::PATH
+        );
+        // polled, so that waiting can give up
+        listener.set_nonblocking(true)?;
+        Ok(LoopbackListener {
+            listener: listener,
+            redirect_uri: redirect_uri,
+            timeout: StdDuration::from_secs(REDIRECT_TIMEOUT_SECS),
         })
-        .ok_or_else(|| ErrorKind::AuthMissingCookie(key.to_owned()).into())
+    }
+
+    /// Blocks until the browser is redirected back to us, and returns the query string. Gives
+    /// up after the timeout, as the user may have closed the page instead
+    fn wait_for_query(&self) -> SpotifyResult<String> {
+        const DONE_PAGE: &str = "<html><body>Authorisation complete, you can close this window.</body></html>";
+        const POLL_MS: u64 = 100;
+        const READ_TIMEOUT_SECS: u64 = 10;
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        bail!(ErrorKind::AuthTimedOut(self.timeout.as_secs()));
+                    }
+                    thread::sleep(StdDuration::from_millis(POLL_MS));
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            stream.set_nonblocking(false)?;
+            // a connection that never sends its request mustn't hold us up either
+            stream.set_read_timeout(Some(StdDuration::from_secs(READ_TIMEOUT_SECS)))?;
+            let mut reader = BufReader::new(stream);
+
+            let mut line = String::new();
+            if let Err(e) = reader.read_line(&mut line) {
+                debug!("Failed to read request to the redirect listener: {}", e);
+                continue;
+            }
+
+            // GET /callback?code=...&state=... HTTP/1.1
+            let target = line.split_whitespace().nth(1).unwrap_or("").to_owned();
+            let mut stream = reader.into_inner();
+
+            if !target.starts_with(LoopbackListener::PATH) {
+                // browsers like to ask for favicons
+                write!(stream, "HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\n")?;
+                continue;
+            }
+
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                DONE_PAGE.len(),
+                DONE_PAGE
+            )?;
+
+            let query = match target.find('?') {
+                Some(i) => target[i + 1..].to_owned(),
+                None => String::new(),
+            };
+            return Ok(query);
+        }
+    }
 }
 
-fn create_cookie(pairs: &[(&str, &str)]) -> Cookie {
-    Cookie(pairs.iter().map(|&(k, v)| format!("{}={}", k, v)).collect())
+fn open_browser(url: &str) {
+    info!("Opening {} in your browser to authorise", url);
+    if let Err(e) = Command::new("xdg-open").arg(url).spawn() {
+        warn!("Failed to open browser ({}), please visit the URL manually", e);
+    }
 }
 
-impl Auth {
-    pub fn new(username: String, password: String) -> Auth {
-        let creds = Creds {
-            username: username,
-            password: password,
+impl AuthFlow {
+    pub fn from_env() -> SpotifyResult<Self> {
+        match env::var(FLOW_ENV).unwrap_or_default().as_str() {
+            "" | "loopback" => Ok(AuthFlow::Loopback),
+            "manual" => Ok(AuthFlow::Manual),
+            other => bail!("unknown auth flow '{}', expected loopback or manual", other),
+        }
+    }
+}
+
+impl Creds {
+    pub fn new(client_id: String) -> Self {
+        Creds {
+            client_id: client_id,
+            client_secret: None,
+        }
+    }
+
+    /// The secret is only needed for app-only authorisation
+    pub fn with_secret(client_id: String, client_secret: String) -> Self {
+        Creds {
+            client_id: client_id,
+            client_secret: Some(client_secret),
+        }
+    }
+
+    /// Parses `client_id[:client_secret]`, as supplied by a credential provider
+    pub fn parse(s: &str) -> SpotifyResult<Self> {
+        let (id, secret) = match s.find(':') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
         };
+
+        if id.is_empty() {
+            bail!(ErrorKind::BadCredentials("client id is empty"));
+        }
+
+        Ok(match secret {
+            Some("") => bail!(ErrorKind::BadCredentials("client secret is empty")),
+            Some(secret) => Creds::with_secret(id.to_owned(), secret.to_owned()),
+            None => Creds::new(id.to_owned()),
+        })
+    }
+}
+
+impl Default for Creds {
+    fn default() -> Self {
+        Creds::new(String::from(SPOTIFY_CLIENT_ID))
+    }
+}
+
+impl Auth {
+    pub fn new(creds: Creds) -> Auth {
         let client = {
             let mut c = Client::new().unwrap();
             c.redirect(RedirectPolicy::none());
@@ -74,179 +353,450 @@ impl Auth {
 
         Auth {
             client: client,
-            state: RefCell::new(None),
+            state: RwLock::new(None),
             creds: creds,
+            mode: AuthMode::User,
+            flow: AuthFlow::Loopback,
+            scopes: Mutex::new(Scopes::from(DEFAULT_SCOPES)),
+            renewal: Mutex::new(()),
+            renewal_margin: Duration::seconds(RENEWAL_MARGIN_SECS),
+            cache_path: filecache::default_path(),
+            cache_encryption: None,
+            endpoints: Endpoints::default(),
+            redirect_port: REDIRECT_PORT,
+            observers: Observers::default(),
+            response_cache: None,
+            rate_limiter: RateLimiter::default(),
+            retry_policy: RetryPolicy::default(),
         }
     }
 
+    /// Authorises as the application alone with the client credentials grant, which only
+    /// gives access to public catalog data
+    pub fn new_app_only(creds: Creds) -> Auth {
+        let mut auth = Auth::new(creds);
+        auth.mode = AuthMode::App;
+        auth
+    }
+
+    /// The scopes to ask for up front, to avoid authorising again when an operation needs more
+    pub fn with_scopes(self, scopes: Scopes) -> Self {
+        *self.scopes.lock().unwrap() = scopes;
+        self
+    }
+
+    /// Renews tokens this long before they expire, to allow for clock skew and slow requests
+    pub fn with_renewal_margin(mut self, margin: Duration) -> Self {
+        self.renewal_margin = margin;
+        self
+    }
+
+    /// Where the token is cached between runs, such as in a profile's directory
+    pub fn with_cache_path(mut self, path: PathBuf) -> Self {
+        self.cache_path = path;
+        self
+    }
+
+    /// Encrypts the token cache and any response cache at rest
+    pub fn with_cache_encryption(mut self, encryption: CacheEncryption) -> Self {
+        self.response_cache = self.response_cache
+            .take()
+            .map(|cache| cache.with_encryption(encryption.clone()));
+        self.cache_encryption = Some(encryption);
+        self
+    }
+
+    /// Points authorisation and API requests somewhere else, such as a local stand-in
+    pub fn with_endpoints(mut self, endpoints: Endpoints) -> Self {
+        self.endpoints = endpoints;
+        self
+    }
+
+    pub fn with_flow(mut self, flow: AuthFlow) -> Self {
+        self.flow = flow;
+        self
+    }
+
+    /// The localhost port to listen on for the authorisation redirect, or 0 for any
+    pub fn with_redirect_port(mut self, port: u16) -> Self {
+        self.redirect_port = port;
+        self
+    }
+
+    /// Told of tokens being loaded, renewed and rejected, and of the user logging in and out
+    pub fn with_observer<O: AuthObserver + 'static>(mut self, observer: O) -> Self {
+        self.observers.add(Box::new(observer));
+        self
+    }
+
+    /// Keeps API responses, so unchanged ones needn't be downloaded again
+    pub fn with_response_cache(mut self, cache: ResponseCache) -> Self {
+        self.response_cache = Some(match self.cache_encryption {
+            Some(ref encryption) => cache.with_encryption(encryption.clone()),
+            None => cache,
+        });
+        self
+    }
+
+    /// Makes every API request in full, without touching the response cache
+    pub fn without_response_cache(mut self) -> Self {
+        self.response_cache = None;
+        self
+    }
+
+    /// Spaces out API requests differently, such as for a stricter quota
+    pub fn with_rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limiter = limiter;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
     #[inline]
     pub fn client(&self) -> &Client {
         &self.client
     }
 
+    #[inline]
+    pub fn endpoints(&self) -> &Endpoints {
+        &self.endpoints
+    }
+
+    #[inline]
+    pub fn mode(&self) -> AuthMode {
+        self.mode
+    }
+
+    #[inline]
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    #[inline]
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    #[inline]
+    pub fn response_cache(&self) -> Option<&ResponseCache> {
+        self.response_cache.as_ref()
+    }
+
     /// Tries to retrieve a valid token, which may involve requesting a new one
     /// Returns a fresh copy, for use in an Authorization header, for example
-    pub fn token(&self, http_client: &Client) -> SpotifyResult<String> {
-        self.ensure_state(http_client)?;
-        Ok(self.state.borrow().as_ref().unwrap().token.clone())
+    pub fn token(&self) -> SpotifyResult<String> {
+        self.token_with_scopes(&Scopes::new())
+    }
+
+    /// As `token`, but authorises again if the token hasn't been granted all of `required`
+    pub fn token_with_scopes(&self, required: &Scopes) -> SpotifyResult<String> {
+        self.ensure_state(required)
+    }
+
+    /// Marks `token` as expired after the server rejected it, so the next request renews it.
+    /// Does nothing if another thread has already replaced or invalidated it
+    pub fn invalidate(&self, token: &str) {
+        let _renewal = self.renewal.lock().unwrap();
+        if let Some(ref mut state) = *self.state.write().unwrap() {
+            if state.token == token && state.expiry_time != 0 {
+                debug!("Token was rejected, it will be renewed");
+                state.expiry_time = 0;
+                self.observers.emit(AuthEvent::TokenRejected);
+            }
+        }
     }
 
-    fn is_state_valid(&self) -> bool {
+    /// Forgets the token, both here and in the cache, so the next request authorises again.
+    /// Spotify has no endpoint to revoke tokens, so the grant itself stays until the user
+    /// removes the app from their account page
+    pub fn logout(&self) -> SpotifyResult<()> {
+        let _renewal = self.renewal.lock().unwrap();
+        let _lock = filecache::lock_at(&self.cache_path)?;
+
+        *self.state.write().unwrap() = None;
+        if filecache::remove(&self.cache_path)? {
+            debug!("Removed cached token {}", self.cache_path.display());
+        }
+        self.observers.emit(AuthEvent::LoggedOut);
+        Ok(())
+    }
+
+    fn valid_token(&self, required: &Scopes) -> Option<String> {
         self.state
-            .borrow()
+            .read()
+            .unwrap()
             .as_ref()
-            .map(|s| s.is_valid())
-            .unwrap_or(false)
+            .and_then(|s| if s.satisfies(required, self.renewal_margin) {
+                Some(s.token.clone())
+            } else {
+                None
+            })
     }
 
+    /// Returns a valid token, renewing it first if needed. Only one thread renews at a time,
+    /// and any others needing a token meanwhile wait for it rather than renewing again
+    fn ensure_state(&self, required: &Scopes) -> SpotifyResult<String> {
+        if let Some(token) = self.valid_token(required) {
+            return Ok(token);
+        }
+
+        let _renewal = self.renewal.lock().unwrap();
 
-    fn ensure_state(&self, http_client: &Client) -> SpotifyResult<()> {
-        if !self.is_state_valid() {
-            // try to load from file
-            {
-                let mut state = self.state.borrow_mut();
-                *state = {
-                    let res = Auth::load();
-                    if let Err(ref e) = res {
-                        debug!("Failed to load token from file: {}", e);
-                    }
-                    res.ok() // ignore error
-                };
+        // renewed by another thread while waiting
+        if let Some(token) = self.valid_token(required) {
+            return Ok(token);
+        }
+
+        let state = match self.mode {
+            AuthMode::User => {
+                // held until the new token is saved, so another process renewing at the
+                // same time finds it in the cache rather than logging in again
+                let _lock = filecache::lock_at(&self.cache_path)?;
+                let state = self.renew_state(required)?;
+                if let Err(e) = self.save(&state) {
+                    warn!("Failed to save token: {}", e);
+                }
+                state
+            }
+            AuthMode::App => {
+                // cheap enough to request again, so don't clobber the user's cache
+                self.request_app_token()?
+            }
+        };
+
+        let token = state.token.clone();
+        *self.state.write().unwrap() = Some(state);
+        Ok(token)
+    }
+
+    /// Finds a valid token from the cache, a refresh, or as a last resort an interactive login
+    fn renew_state(&self, required: &Scopes) -> SpotifyResult<AuthState> {
+        // another session may have left a fresher token in the cache
+        let current = self.state.read().unwrap().clone();
+        let (previous, loaded) = match self.load() {
+            // the cache can't know that the server has since rejected this token
+            Ok(ref state) if current.as_ref().map(|c| c.token == state.token) == Some(true) => {
+                (current, false)
+            }
+            Ok(state) => (Some(state), true),
+            Err(e @ Error(ErrorKind::TokenCacheEncrypted, _)) |
+            Err(e @ Error(ErrorKind::TokenCacheDecryptFailed, _)) => {
+                // logging in again would overwrite the cache, which may still be wanted
+                return Err(e);
             }
-            if !self.is_state_valid() {
-                {
-                    // authorise again
-                    let mut state = self.state.borrow_mut();
-                    *state = Some(Auth::authorise(&self.creds, http_client)?);
+            Err(e) => {
+                debug!("Failed to load token from file: {}", e);
+                (current, false)
+            }
+        };
+
+        let reason = if let Some(previous) = previous {
+            if previous.satisfies(required, self.renewal_margin) {
+                if loaded {
+                    self.observers.emit(AuthEvent::TokenLoaded);
                 }
+                return Ok(previous);
+            }
+
+            if !previous.scopes.contains_all(required) {
+                // a refresh can't widen the grant, so keep what we had and ask for the rest
+                let mut scopes = self.scopes.lock().unwrap();
+                *scopes = scopes.union(&previous.scopes).union(required);
+                debug!("Token lacks required scopes, authorising again for '{}'", *scopes);
+                ReauthReason::MissingScopes(scopes.clone())
+            } else if let Some(ref refresh_token) = previous.refresh_token {
+                match self.refresh(refresh_token, &previous.scopes) {
+                    Ok(state) => {
+                        self.observers.emit(AuthEvent::TokenRefreshed);
+                        return Ok(state);
+                    }
+                    Err(e) => {
+                        debug!("Failed to refresh token: {}", e);
+                        ReauthReason::Expired
+                    }
+                }
+            } else {
+                ReauthReason::Expired
+            }
+        } else {
+            let mut scopes = self.scopes.lock().unwrap();
+            *scopes = scopes.union(required);
+            ReauthReason::NoToken
+        };
+
+        // authorise again
+        self.observers.emit(AuthEvent::ReauthRequired(reason));
+        match self.authorise() {
+            Ok(state) => {
+                self.observers.emit(AuthEvent::LoggedIn);
+                Ok(state)
+            }
+            Err(e) => {
+                self.observers.emit(AuthEvent::LoginFailed(&e));
+                Err(e)
             }
         }
+    }
 
-        self.save().ok();
-        Ok(())
+    fn authorise(&self) -> SpotifyResult<AuthState> {
+        let pkce = Pkce::new();
+        let csrf_state = random_string(16);
+
+        let mut state = match self.flow {
+            AuthFlow::Loopback => {
+                let listener = LoopbackListener::bind(self.redirect_port)?;
+                let url = self.authorise_url(&listener.redirect_uri, &pkce, &csrf_state)?;
+                open_browser(url.as_str());
+
+                let code = Auth::wait_for_code(&listener, &csrf_state)?;
+                self.exchange_code(&code, &listener.redirect_uri, &pkce)?
+            }
+            AuthFlow::Manual => {
+                let stdin = io::stdin();
+                let mut input = stdin.lock();
+                self.authorise_manually(&mut input, &mut io::stderr(), &pkce, &csrf_state)?
+            }
+        };
+
+        // only for recording in the cache, so not worth failing over
+        match self.lookup_account(&state.token) {
+            Ok(account) => state.account = Some(account),
+            Err(e) => debug!("Failed to look up account for token: {}", e),
+        }
+        Ok(state)
     }
 
+    fn lookup_account(&self, token: &str) -> SpotifyResult<String> {
+        let mut response = self.client
+            .get(self.endpoints.api_url("me")?)
+            .header(Authorization(Bearer { token: token.to_owned() }))
+            .send()?;
 
-    fn authorise(creds: &Creds, client: &Client) -> SpotifyResult<AuthState> {
-        const AUTHORIZE: &str = "https://accounts.spotify.com/authorize?";
-        const LOGIN: &str = "https://accounts.spotify.com/api/login";
-        const ACCEPT: &str = "https://accounts.spotify.com/en/authorize/accept";
-        const SPOTIFY_CLIENT_ID: &str = "a4a869822602493c828f424d7552379c";
-        const REDIRECT_URI: &str = "http://localhost";
-        const SCOPE: &str = "user-library-read";
+        if !response.status().is_success() {
+            bail!(ErrorKind::BadResponseStatusCode(*response.status()));
+        }
 
-        // initial authorise attempt
-        let query_params = vec![
-            ("client_id", String::from(SPOTIFY_CLIENT_ID)),
-            ("response_type", String::from("token")),
-            ("redirect_uri", String::from(REDIRECT_URI)),
-            ("scope", String::from(SCOPE)),
-            ("show_dialog", String::from("true")),
-        ];
+        let mut raw = String::new();
+        response.read_to_string(&mut raw)?;
+        json::parse(&raw)
+            .ok()
+            .and_then(|me| me["id"].as_str().map(|s| s.to_owned()))
+            .ok_or_else(|| ErrorKind::AuthBadResponse("missing user id").into())
+    }
+
+    fn authorise_url(&self, redirect_uri: &str, pkce: &Pkce, csrf_state: &str) -> SpotifyResult<Url> {
+        let mut url = self.endpoints.accounts_url("authorize")?;
+        url.query_pairs_mut()
+            .append_pair("client_id", &self.creds.client_id)
+            .append_pair("response_type", "code")
+            .append_pair("redirect_uri", redirect_uri)
+            .append_pair("code_challenge_method", "S256")
+            .append_pair("code_challenge", &pkce.challenge)
+            .append_pair("scope", &self.scopes.lock().unwrap().to_string())
+            .append_pair("state", csrf_state);
+        Ok(url)
+    }
+
+    fn wait_for_code(listener: &LoopbackListener, csrf_state: &str) -> SpotifyResult<String> {
+        debug!("Waiting for authorisation redirect to {}", listener.redirect_uri);
+        let query = listener.wait_for_query()?;
+        code_from_query(&Params::parse(&query), csrf_state)
+    }
 
-        let mut headers = {
-            let mut h = Headers::new();
-            h.set(UserAgent(
-                "Mozilla/5.0 (X11; Linux x86_64; rv:54.0) Gecko/20100101 Firefox/54.0"
-                    .to_owned(),
-            ));
-            h.set(Connection::keep_alive());
-            h
+    /// The redirect goes nowhere, as nothing is listening, but the browser's address bar still
+    /// holds the URL it was sent to
+    fn authorise_manually(
+        &self,
+        input: &mut dyn BufRead,
+        output: &mut dyn Write,
+        pkce: &Pkce,
+        csrf_state: &str,
+    ) -> SpotifyResult<AuthState> {
+        let port = match self.redirect_port {
+            0 => REDIRECT_PORT,
+            port => port,
         };
+        let redirect_uri = format!("http://127.0.0.1:{}{}", port, LoopbackListener::PATH);
+        let url = self.authorise_url(&redirect_uri, pkce, csrf_state)?;
 
-        let original_url = url::form_urlencoded::Serializer::new(String::from(AUTHORIZE))
-            .extend_pairs(&query_params)
-            .finish();
-        debug!("Sending GET to /authorize");
-        let resp = client
-            .get(original_url.as_str())
-            .headers(headers.clone())
-            .send()?;
+        writeln!(output, "Open this URL in a browser on any machine to authorise:\n\n{}\n", url)?;
+        writeln!(
+            output,
+            "The page it redirects to will fail to load. Paste its full URL here:"
+        )?;
 
-        let csrf = extract_cookie_value(resp.headers(), CSRF)?;
+        let mut pasted = String::new();
+        input.read_line(&mut pasted)?;
 
-        let login_data = vec![
-            ("remember", "false"),
-            ("username", &creds.username),
-            ("password", &creds.password),
-            ("csrf_token", csrf),
+        let code = parse_pasted_redirect(pasted.trim(), csrf_state)?;
+        self.exchange_code(&code, &redirect_uri, pkce)
+    }
+
+    fn exchange_code(&self, code: &str, redirect_uri: &str, pkce: &Pkce) -> SpotifyResult<AuthState> {
+        debug!("Exchanging authorisation code for a token");
+        let params = [
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", &self.creds.client_id),
+            ("code_verifier", &pkce.verifier),
         ];
-        let login_cookies = create_cookie(
-            &[
-                (CSRF, csrf),
-                ("__bon", "MHwwfDYyODMzMzc0OHwyNjM5MDAxNzQxNnwxfDF8MXww"),
-                ("fb_continue", &original_url),
-                ("remember", &creds.username),
-            ],
-        );
+        let response = self.request_token(&params)?;
+        AuthState::from_token_response(&response, &self.scopes.lock().unwrap())
+    }
 
-        headers.set(Referer(original_url.clone()));
-        headers.set(login_cookies);
+    fn refresh(&self, refresh_token: &str, scopes: &Scopes) -> SpotifyResult<AuthState> {
+        debug!("Refreshing access token");
+        let params = [
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+            ("client_id", &self.creds.client_id),
+        ];
+        let response = self.request_token(&params)?;
+        let mut state = AuthState::from_token_response(&response, scopes)?;
 
-        debug!("Sending POST to /login");
-        let resp = client
-            .post(LOGIN)
-            .headers(headers.clone())
-            .form(&login_data)
-            .send()?;
+        // the same refresh token stays valid if a new one isn't issued
+        if state.refresh_token.is_none() {
+            state.refresh_token = Some(refresh_token.to_owned());
+        }
+        Ok(state)
+    }
 
-        if !resp.status().is_success() {
-            bail!(ErrorKind::AuthBadCreds);
+    fn request_app_token(&self) -> SpotifyResult<AuthState> {
+        debug!("Requesting app-only token");
+        if self.creds.client_secret.is_none() {
+            bail!(ErrorKind::AuthMissingClientSecret);
         }
 
-        debug!("Authenticated!");
+        let response = self.request_token(&[("grant_type", "client_credentials")])?;
+        AuthState::from_token_response(&response, &Scopes::new())
+    }
 
-        let csrf = extract_cookie_value(resp.headers(), CSRF)?;
-        let accept_data = {
-            let mut pairs = query_params;
-            pairs.push((CSRF, csrf.to_owned()));
-            pairs
-        };
-        let accept_cookies = create_cookie(
-            &[
-                ("sp_ac", extract_cookie_value(resp.headers(), "sp_ac")?),
-                ("sp_dc", extract_cookie_value(resp.headers(), "sp_dc")?),
-                (CSRF, extract_cookie_value(resp.headers(), CSRF)?),
-            ],
-        );
-        headers.remove::<Cookie>();
-        headers.set(accept_cookies);
-
-        debug!("Sending POST to /accept");
-        let resp = client
-            .post(ACCEPT)
-            .headers(headers)
-            .form(&accept_data)
-            .send()?;
+    /// POSTs the given form to the token endpoint, returning the parsed response
+    fn request_token(&self, params: &[(&str, &str)]) -> SpotifyResult<JsonValue> {
+        let url = self.endpoints.accounts_url("api/token")?;
+        let mut request = self.client.post(url).form(&params);
+        if let Some(ref secret) = self.creds.client_secret {
+            request = request.basic_auth(self.creds.client_id.clone(), Some(secret.clone()));
+        }
+        let mut response = request.send().chain_err(|| ErrorKind::AuthNetwork)?;
 
-        resp.headers()
-            .get::<Location>()
-            .and_then(|&Location(ref loc)| {
-                let e = extract_from_flattened_list(loc, "expires_in", '&');
-                let t = extract_from_flattened_list(loc, "access_token", '&');
-
-                match (e, t) {
-                    (Some(e), Some(t)) => {
-                        Some(AuthState {
-                            token: t.to_owned(),
-                            expiry_time: time::get_time().sec + e.parse::<i64>().unwrap(),
-                        })
-                    }
-                    _ => None,
-                }
-            })
-            .ok_or_else(|| ErrorKind::AuthFailedAccept.into())
+        let mut raw = String::new();
+        response.read_to_string(&mut raw).chain_err(|| ErrorKind::AuthNetwork)?;
+        diagnose_token_response(&response, &raw)?;
+
+        json::parse(&raw).chain_err(|| ErrorKind::AuthBadResponse("token response is not json"))
     }
 
-    fn save(&self) -> SpotifyResult<()> {
-        match *self.state.borrow() {
-            Some(ref state) => filecache::save(state),
-            None => Err(ErrorKind::BadTokenCache("No token to cache").into()),
-        }
+    fn save(&self, state: &AuthState) -> SpotifyResult<()> {
+        filecache::save_to(&self.cache_path, state, self.cache_encryption.as_ref())
     }
 
-    fn load() -> SpotifyResult<AuthState> {
-        let r = filecache::load();
+    fn load(&self) -> SpotifyResult<AuthState> {
+        let r = filecache::load_from(&self.cache_path, self.cache_encryption.as_ref());
         if r.is_ok() {
             debug!("Loaded token from file successfully");
         }
@@ -255,115 +805,429 @@ impl Auth {
 }
 
 impl AuthState {
+    /// `requested` is assumed to have been granted if the response doesn't say otherwise
+    fn from_token_response(response: &JsonValue, requested: &Scopes) -> SpotifyResult<Self> {
+        let token = response["access_token"].as_str().ok_or_else(|| {
+            Error::from(ErrorKind::AuthBadResponse("missing access_token"))
+        })?;
+        let expires_in = response["expires_in"].as_i64().ok_or_else(|| {
+            Error::from(ErrorKind::AuthBadResponse("missing expires_in"))
+        })?;
+
+        Ok(AuthState {
+            token: token.to_owned(),
+            token_type: response["token_type"].as_str().unwrap_or("Bearer").to_owned(),
+            expiry_time: time::get_time().sec + expires_in,
+            refresh_token: response["refresh_token"].as_str().map(|s| s.to_owned()),
+            scopes: match response["scope"].as_str() {
+                Some(s) => Scopes::parse(s),
+                None => requested.clone(),
+            },
+            account: None,
+        })
+    }
+
     pub fn is_valid(&self) -> bool {
-        self.expiry_time > time::get_time().sec
+        self.is_valid_for(Duration::zero())
+    }
+
+    /// Whether the token will still be valid after `margin` has passed
+    pub fn is_valid_for(&self, margin: Duration) -> bool {
+        self.expiry_time - margin.num_seconds() > time::get_time().sec
+    }
+
+    pub fn satisfies(&self, required: &Scopes, margin: Duration) -> bool {
+        self.is_valid_for(margin) && self.scopes.contains_all(required)
     }
 }
 
-mod filecache {
-    use std::path::PathBuf;
-    use spotify;
-    use error::*;
-    use std::fs::File;
-    use std::io::{Write, BufRead, BufReader};
+#[cfg(test)]
+mod test {
+    use http::auth::*;
+    use http::testserver::{TestServer, Response};
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::thread;
 
-    use http::auth;
+    #[test]
+    fn pkce_challenge() {
+        let pkce = Pkce::from_verifier(String::from("dBjftJeZ4CVP-mJ0kzjzZFYJ8MhK7N9JZqI5UNDojds"));
+        assert_eq!(pkce.challenge, "qFxgBHrNCLRAWca5a6BshlrV1vQrGOoakSu7Yq8lkLs");
 
-    fn state_path() -> PathBuf {
-        const STATE_FILE: &str = "state.conf";
-        let mut path = spotify::config_dir();
-        path.push(STATE_FILE);
-        path
+        let pkce = Pkce::new();
+        assert_eq!(pkce.verifier.len(), 64);
+        assert_eq!(Pkce::from_verifier(pkce.verifier).challenge, pkce.challenge);
     }
 
-    pub fn save(state: &auth::AuthState) -> SpotifyResult<()> {
-        let mut f = File::create(state_path())?;
-        write!(&mut f, "{}\n{}", state.token, state.expiry_time)?;
-        Ok(())
+    fn redirect_to(listener: &LoopbackListener, path: &str) {
+        let addr = listener.listener.local_addr().unwrap();
+        let path = path.to_owned();
+        thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).ok();
+        });
     }
 
-    fn trim_in_place(s: &mut String) {
-        if s.ends_with('\n') {
-            s.pop();
+    #[test]
+    fn loopback_redirect() {
+        let listener = LoopbackListener::bind(0).unwrap();
+        assert!(listener.redirect_uri.starts_with("http://127.0.0.1:"));
+        assert!(listener.redirect_uri.ends_with("/callback"));
+
+        redirect_to(&listener, "/favicon.ico");
+        redirect_to(&listener, "/callback?code=abcdef&state=xyz");
+        assert_eq!(Auth::wait_for_code(&listener, "xyz").unwrap(), "abcdef");
+
+        redirect_to(&listener, "/callback?code=abcdef&state=forged");
+        match Auth::wait_for_code(&listener, "xyz") {
+            Err(Error(ErrorKind::AuthStateMismatch, _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+
+        redirect_to(&listener, "/callback?error=access_denied&state=xyz");
+        match Auth::wait_for_code(&listener, "xyz") {
+            Err(Error(ErrorKind::AuthDeclined, _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+
+        // the browser never comes back
+        let mut listener = listener;
+        listener.timeout = StdDuration::from_millis(200);
+        match Auth::wait_for_code(&listener, "xyz") {
+            Err(Error(ErrorKind::AuthTimedOut(_), _)) => (),
+            _ => assert!(false, "Error not returned"),
         }
     }
 
-    pub fn load() -> SpotifyResult<auth::AuthState> {
-        let f = File::open(state_path())?;
-        let mut reader = BufReader::new(f);
+    #[test]
+    fn manual_paste() {
+        let server = TestServer::start(|req| match req.form_value("code") {
+            Some(ref c) if c == "abcdef" => Response::json(
+                200,
+                r#"{"access_token": "pasted", "expires_in": 3600, "refresh_token": "r"}"#,
+            ),
+            _ => Response::json(400, r#"{"error": "invalid_grant"}"#),
+        });
+        let auth = Auth::new(Creds::default())
+            .with_flow(AuthFlow::Manual)
+            .with_endpoints(server.endpoints());
+        let pkce = Pkce::new();
 
-        let mut token = String::new();
-        let mut expiry = String::new();
-        reader.read_line(&mut token).chain_err(|| {
-            ErrorKind::BadTokenCache("Token missing")
-        })?;
-        reader.read_line(&mut expiry).chain_err(|| {
-            ErrorKind::BadTokenCache("Expiry time missing")
-        })?;
+        let mut output = Vec::new();
+        let mut input = "  http://127.0.0.1:8888/callback?code=abcdef&state=xyz\n".as_bytes();
+        let state = auth.authorise_manually(&mut input, &mut output, &pkce, "xyz").unwrap();
+        assert_eq!(state.token, "pasted");
+        assert_eq!(state.refresh_token, Some(String::from("r")));
 
-        // remove new lines
-        trim_in_place(&mut token);
-        trim_in_place(&mut expiry);
-        Ok(auth::AuthState {
-            token: token,
-            expiry_time: expiry.parse().chain_err(|| {
-                ErrorKind::BadTokenCache("Expiry time is not a number")
-            })?,
-        })
+        // the printed URL redirects to where the code is exchanged for
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains(&format!("{}/authorize?", server.url())));
+        assert_eq!(
+            server.requests()[0].form_value("redirect_uri").unwrap(),
+            "http://127.0.0.1:8888/callback"
+        );
+
+        match parse_pasted_redirect("?code=abcdef&state=forged", "xyz") {
+            Err(Error(ErrorKind::AuthStateMismatch, _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+        // a token can't be pasted in place of a code, skipping PKCE
+        match parse_pasted_redirect("#access_token=a&expires_in=3600&state=xyz", "xyz") {
+            Err(Error(ErrorKind::AuthStateMismatch, _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+        match parse_pasted_redirect("", "xyz") {
+            Err(Error(ErrorKind::AuthStateMismatch, _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
     }
 
+    #[test]
+    fn code_exchange() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/api/token" => Response::json(
+                200,
+                r#"{"access_token": "NgCXRK", "token_type": "Bearer", "expires_in": 3600}"#,
+            ),
+            _ => Response::new(404),
+        });
+        let auth = Auth::new(Creds::new(String::from("client")))
+            .with_endpoints(server.endpoints());
 
-}
+        let pkce = Pkce::new();
+        let url = auth.authorise_url("http://127.0.0.1:1/callback", &pkce, "xyz").unwrap();
+        assert!(url.as_str().starts_with(server.url()));
+        assert!(url.query_pairs().any(|(k, v)| k == "code_challenge" && v == pkce.challenge));
+        assert!(url.query_pairs().any(|(k, v)| k == "scope" && v == "user-library-read"));
 
-#[cfg(test)]
-mod test {
-    use http::auth::*;
-    use reqwest::header::{Headers, SetCookie, Cookie};
+        let state = auth.exchange_code("abcdef", "http://127.0.0.1:1/callback", &pkce)
+            .unwrap();
+        assert_eq!(state.token, "NgCXRK");
+        assert!(state.is_valid());
+        assert!(state.satisfies(&Scopes::from(DEFAULT_SCOPES), Duration::zero()));
+        assert!(!state.satisfies(&Scopes::from(&[Scope::UserTopRead][..]), Duration::zero()));
+
+        // renewed well before it actually expires
+        assert!(state.is_valid_for(Duration::minutes(59)));
+        assert!(!state.is_valid_for(Duration::minutes(61)));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].form_value("grant_type").unwrap(), "authorization_code");
+        assert_eq!(requests[0].form_value("code").unwrap(), "abcdef");
+        assert_eq!(requests[0].form_value("code_verifier").unwrap(), pkce.verifier);
+    }
 
     #[test]
-    fn flattened_list_extraction() {
-        const URL: &'static str = "http://localhost:8080#banana=good&big-scary-dogs=bad&boris=johnson";
+    fn rejected_code_exchange() {
+        let server = TestServer::start(|_| Response::json(400, r#"{"error": "invalid_grant"}"#));
+        let auth = Auth::new(Creds::default()).with_endpoints(server.endpoints());
 
+        match auth.exchange_code("abcdef", "http://127.0.0.1:1/callback", &Pkce::new()) {
+            Err(Error(ErrorKind::AuthInvalidGrant(_), _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+    }
+
+    #[test]
+    fn failure_diagnosis() {
+        fn exchange_with(response: Response) -> SpotifyResult<AuthState> {
+            let response = ::std::sync::Mutex::new(Some(response));
+            let server = TestServer::start(move |_| response.lock().unwrap().take().unwrap());
+            let auth = Auth::new(Creds::default()).with_endpoints(server.endpoints());
+            auth.exchange_code("abcdef", "http://127.0.0.1:1/callback", &Pkce::new())
+        }
+
+        match exchange_with(Response::json(
+            400,
+            r#"{"error": "invalid_client", "error_description": "Invalid client"}"#,
+        )) {
+            Err(Error(ErrorKind::AuthInvalidClient(ref d), _)) => assert_eq!(d, "Invalid client"),
+            _ => assert!(false, "Error not returned"),
+        }
+        match exchange_with(Response::json(401, "{}")) {
+            Err(Error(ErrorKind::AuthInvalidClient(_), _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+        match exchange_with(Response::json(429, "{}").header("Retry-After", "30")) {
+            Err(Error(ErrorKind::AuthRateLimited(Some(30)), _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+        match exchange_with(Response::new(403).body("<html><form id=\"captcha\"></form></html>")) {
+            Err(Error(ErrorKind::AuthChallenge, _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+        match exchange_with(Response::json(200, r#"{"token": "moved"}"#)) {
+            Err(Error(ErrorKind::AuthBadResponse(_), _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+        match exchange_with(Response::json(500, r#"{"error": "server_error"}"#)) {
+            Err(Error(ErrorKind::AuthTokenRequestFailed(status, ref e), _)) => {
+                assert_eq!(status, StatusCode::InternalServerError);
+                assert_eq!(e, "server_error");
+            }
+            _ => assert!(false, "Error not returned"),
+        }
+
+        // nothing listening
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let url = Url::parse(&format!("http://127.0.0.1:{}", port)).unwrap();
+        let auth = Auth::new(Creds::default()).with_endpoints(Endpoints::stand_in(&url).unwrap());
+        match auth.exchange_code("abcdef", "http://127.0.0.1:1/callback", &Pkce::new()) {
+            Err(Error(ErrorKind::AuthNetwork, _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+
+        assert!(ErrorKind::AuthChallenge.hint().is_some());
+        assert!(ErrorKind::NotImplemented.hint().is_none());
+    }
+
+    #[test]
+    fn logout() {
+        let path = ::std::env::temp_dir().join("spotify-model-test-logout");
+        let state = AuthState {
+            token: String::from("cached"),
+            token_type: String::from("Bearer"),
+            expiry_time: time::get_time().sec + 3600,
+            refresh_token: Some(String::from("refresh")),
+            scopes: Scopes::from(DEFAULT_SCOPES),
+            account: None,
+        };
+        filecache::save_to(&path, &state, None).unwrap();
+
+        let auth = Auth::new(Creds::default()).with_cache_path(path.clone());
+        assert_eq!(auth.token().unwrap(), "cached");
+
+        let debug = format!("{:?}", auth);
+        assert!(!debug.contains("cached") && !debug.contains("\"refresh\""));
+
+        auth.logout().unwrap();
+        assert!(auth.state.read().unwrap().is_none());
+        assert!(!path.exists());
+
+        // nothing left to log out of
+        auth.logout().unwrap();
+    }
+
+    #[test]
+    fn token_refresh() {
+        let server = TestServer::start(|req| match req.form_value("refresh_token") {
+            Some(ref t) if t == "first" => Response::json(
+                200,
+                r#"{"access_token": "a", "expires_in": 3600, "refresh_token": "second"}"#,
+            ),
+            Some(ref t) if t == "second" => Response::json(
+                200,
+                r#"{"access_token": "b", "expires_in": 3600}"#,
+            ),
+            _ => Response::json(400, r#"{"error": "invalid_grant"}"#),
+        });
+        let auth = Auth::new(Creds::default()).with_endpoints(server.endpoints());
+
+        // a new refresh token replaces the old one
+        let state = auth.refresh("first", &Scopes::new()).unwrap();
+        assert_eq!(state.token, "a");
+        assert_eq!(state.refresh_token, Some(String::from("second")));
+
+        // otherwise the old one is kept
+        let state = auth.refresh("second", &Scopes::new()).unwrap();
+        assert_eq!(state.token, "b");
+        assert_eq!(state.refresh_token, Some(String::from("second")));
+
+        assert!(auth.refresh("revoked", &Scopes::new()).is_err());
         assert_eq!(
-            extract_from_flattened_list(URL, "banana", '&'),
-            Some("good")
-        );
-        assert_eq!(
-            extract_from_flattened_list(URL, "big-scary-dogs", '&'),
-            Some("bad")
+            server.requests()[0].form_value("grant_type").unwrap(),
+            "refresh_token"
         );
+    }
+
+    #[test]
+    fn app_only_token() {
+        let server = TestServer::start(|req| match req.form_value("grant_type") {
+            Some(ref g) if g == "client_credentials" => Response::json(
+                200,
+                r#"{"access_token": "app", "token_type": "Bearer", "expires_in": 3600}"#,
+            ),
+            _ => Response::json(400, r#"{"error": "unsupported_grant_type"}"#),
+        });
+        let auth = Auth::new_app_only(Creds::new(String::from("id")))
+            .with_endpoints(server.endpoints());
+        match auth.token() {
+            Err(Error(ErrorKind::AuthMissingClientSecret, _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+
+        let auth = Auth::new_app_only(Creds::with_secret(String::from("id"), String::from("secret")))
+            .with_endpoints(server.endpoints());
+        assert_eq!(auth.mode(), AuthMode::App);
+        assert_eq!(auth.token().unwrap(), "app");
+
+        // base64 of id:secret
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].header("Authorization"), Some("Basic aWQ6c2VjcmV0"));
+    }
+
+    #[test]
+    fn lifecycle_events() {
+        let server = TestServer::start(|_| {
+            Response::json(200, r#"{"access_token": "refreshed", "expires_in": 3600}"#)
+        });
+        let path = ::std::env::temp_dir().join("spotify-model-test-events");
+        let mut state = AuthState {
+            token: String::from("expired"),
+            token_type: String::from("Bearer"),
+            expiry_time: 0,
+            refresh_token: Some(String::from("refresh")),
+            scopes: Scopes::from(DEFAULT_SCOPES),
+            account: None,
+        };
+        filecache::save_to(&path, &state, None).unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let observed = |events: &Arc<Mutex<Vec<String>>>| {
+            let events = events.clone();
+            move |e: &AuthEvent| events.lock().unwrap().push(format!("{:?}", e))
+        };
+        let auth = Auth::new(Creds::default())
+            .with_endpoints(server.endpoints())
+            .with_cache_path(path.clone())
+            .with_observer(observed(&events));
+
+        assert_eq!(auth.token().unwrap(), "refreshed");
+        auth.invalidate("refreshed");
+        auth.invalidate("refreshed");
+        assert_eq!(auth.token().unwrap(), "refreshed");
+        auth.logout().unwrap();
         assert_eq!(
-            extract_from_flattened_list(URL, "boris", '&'),
-            Some("johnson")
+            *events.lock().unwrap(),
+            ["TokenRefreshed", "TokenRejected", "TokenRefreshed", "LoggedOut"]
         );
-        assert_eq!(extract_from_flattened_list(URL, "nonexistent", '&'), None);
 
-        assert_eq!(extract_from_flattened_list("", "", ' '), None);
-        assert_eq!(extract_from_flattened_list("&junk&", "nothing", '&'), None);
+        // a valid token in the cache is loaded once, and then used from memory
+        state.expiry_time = time::get_time().sec + 3600;
+        filecache::save_to(&path, &state, None).unwrap();
+        events.lock().unwrap().clear();
+        let auth = Auth::new(Creds::default())
+            .with_cache_path(path.clone())
+            .with_observer(observed(&events));
+        assert_eq!(auth.token().unwrap(), "expired");
+        assert_eq!(auth.token().unwrap(), "expired");
+        assert_eq!(*events.lock().unwrap(), ["TokenLoaded"]);
+
+        filecache::remove(&path).unwrap();
     }
 
     #[test]
-    fn cookie_extraction() {
-        let headers = {
-            let mut h = Headers::new();
-            h.set(SetCookie(
-                vec!["test=message; Path=/; SomethingElse=lala".to_owned()],
-            ));
-            h
-        };
+    fn single_flight_renewal() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Auth>();
 
-        assert_eq!(extract_cookie_value(&headers, "test").ok(), Some("message"));
-        match extract_cookie_value(&headers, "nonexistent") {
-            Err(Error(ErrorKind::AuthMissingCookie(_), _)) => (),
-            _ => assert!(false, "Error not returned"),
+        let server = TestServer::start(|_| {
+            // long enough for every thread to find the token missing
+            thread::sleep(::std::time::Duration::from_millis(200));
+            Response::json(200, r#"{"access_token": "app", "expires_in": 3600}"#)
+        });
+        let auth = Arc::new(
+            Auth::new_app_only(Creds::with_secret(String::from("id"), String::from("secret")))
+                .with_endpoints(server.endpoints()),
+        );
+
+        let threads = (0..8)
+            .map(|_| {
+                let auth = auth.clone();
+                thread::spawn(move || auth.token().unwrap())
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            assert_eq!(t.join().unwrap(), "app");
         }
+
+        assert_eq!(server.requests().len(), 1);
     }
 
     #[test]
-    fn cookie_creation() {
-        let pairs = [("harold", "robinson"), ("awful", "man")];
-        let cookie = create_cookie(&pairs);
-        let Cookie(key_value) = cookie;
-        assert_eq!(key_value, ["harold=robinson", "awful=man"]);
+    fn creds_parsing() {
+        let creds = Creds::parse("id:secret").unwrap();
+        assert_eq!(creds.client_id, "id");
+        assert_eq!(creds.client_secret, Some(String::from("secret")));
+        assert_eq!(
+            format!("{:?}", creds),
+            "Creds { client_id: \"id\", client_secret: Some(<redacted>) }"
+        );
+
+        let creds = Creds::parse("id").unwrap();
+        assert_eq!(creds.client_id, "id");
+        assert_eq!(creds.client_secret, None);
+
+        for bad in &["", ":secret", "id:"] {
+            match Creds::parse(bad) {
+                Err(Error(ErrorKind::BadCredentials(_), _)) => (),
+                _ => assert!(false, "Error not returned"),
+            }
+        }
     }
 }
@@ -0,0 +1,213 @@
+//! Opt-in encryption of the token cache, with a key derived from a passphrase or key file
+
+use argon2::Argon2;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use rand::{OsRng, Rng};
+use base64;
+use json::JsonValue;
+use error::*;
+use credentials::{ProviderChain, Secret};
+
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Selects the cache encryption: unset or `none`, `passphrase`, or `keyfile:<path>`
+pub const ENCRYPTION_ENV: &str = "SPOTIFY_CACHE_ENCRYPTION";
+
+pub const CACHE_PASSPHRASE: Secret = Secret {
+    env_var: "SPOTIFY_CACHE_PASSPHRASE",
+    file_name: "cache-passphrase",
+    command_env_var: "SPOTIFY_CACHE_PASSPHRASE_COMMAND",
+    prompt: "Token cache passphrase: ",
+    providers_env_var: "SPOTIFY_CACHE_PASSPHRASE_PROVIDERS",
+    // a passphrase saved next to the cache it protects would protect nothing, so the file is
+    // only read if asked for
+    default_providers: "env,command,prompt",
+};
+
+const ALGORITHM: &str = "argon2id-chacha20poly1305";
+pub const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+#[derive(Clone)]
+pub struct CacheEncryption {
+    secret: Vec<u8>,
+}
+
+impl fmt::Debug for CacheEncryption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("CacheEncryption { .. }")
+    }
+}
+
+/// A key derived from the secret and a salt, to seal many documents without deriving again for
+/// each. Every document gets a fresh nonce, and the salt is kept by whoever derived the key
+#[derive(Clone)]
+pub struct DerivedKey {
+    key: [u8; 32],
+}
+
+impl fmt::Debug for DerivedKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("DerivedKey { .. }")
+    }
+}
+
+fn random_bytes(len: usize) -> SpotifyResult<Vec<u8>> {
+    let mut bytes = vec![0; len];
+    OsRng::new()?.fill_bytes(&mut bytes);
+    Ok(bytes)
+}
+
+fn decode_field(envelope: &JsonValue, key: &str, len: Option<usize>) -> SpotifyResult<Vec<u8>> {
+    let bytes = envelope[key]
+        .as_str()
+        .and_then(|s| base64::decode(s).ok())
+        .ok_or_else(|| {
+            Error::from(ErrorKind::BadTokenCache(format!("encrypted {} is missing", key)))
+        })?;
+
+    match len {
+        Some(len) if bytes.len() != len => Err(
+            ErrorKind::BadTokenCache(format!("encrypted {} has the wrong length", key))
+                .into(),
+        ),
+        _ => Ok(bytes),
+    }
+}
+
+impl CacheEncryption {
+    pub fn from_passphrase(passphrase: String) -> Self {
+        CacheEncryption { secret: passphrase.into_bytes() }
+    }
+
+    pub fn from_key_file(path: &Path) -> SpotifyResult<Self> {
+        let mut secret = Vec::new();
+        File::open(path)?.read_to_end(&mut secret)?;
+        if secret.is_empty() {
+            bail!(ErrorKind::BadCredentials("cache key file is empty"));
+        }
+        Ok(CacheEncryption { secret: secret })
+    }
+
+    /// Reads `SPOTIFY_CACHE_ENCRYPTION`, fetching the passphrase from the credential providers
+    /// if needed. `None` means the cache should be left unencrypted
+    pub fn from_env() -> SpotifyResult<Option<Self>> {
+        let mode = env::var(ENCRYPTION_ENV).unwrap_or_default();
+        match mode.as_str() {
+            "" | "none" => Ok(None),
+            "passphrase" => {
+                let providers = ProviderChain::from_env(&CACHE_PASSPHRASE)?;
+                Ok(Some(CacheEncryption::from_passphrase(providers.fetch()?)))
+            }
+            _ if mode.starts_with("keyfile:") => {
+                CacheEncryption::from_key_file(Path::new(&mode["keyfile:".len()..])).map(Some)
+            }
+            _ => bail!(ErrorKind::BadCredentials("unknown cache encryption mode")),
+        }
+    }
+
+    pub fn new_salt() -> SpotifyResult<Vec<u8>> {
+        random_bytes(SALT_LEN)
+    }
+
+    /// Derives the key for `salt`, which is deliberately slow
+    pub fn derive(&self, salt: &[u8]) -> SpotifyResult<DerivedKey> {
+        let mut key = [0; 32];
+        Argon2::default()
+            .hash_password_into(&self.secret, salt, &mut key)
+            .map_err(|_| Error::from("Failed to derive cache key"))?;
+        Ok(DerivedKey { key: key })
+    }
+
+    /// Is this document an encrypted envelope, rather than a plain cache
+    pub fn is_envelope(doc: &JsonValue) -> bool {
+        doc.has_key("ciphertext")
+    }
+
+    /// Seals `plaintext` under a key derived from a fresh salt, which is kept in the envelope
+    pub fn encrypt(&self, plaintext: &[u8]) -> SpotifyResult<JsonValue> {
+        let salt = CacheEncryption::new_salt()?;
+        let mut envelope = self.derive(&salt)?.seal(plaintext)?;
+        envelope["salt"] = base64::encode(&salt).into();
+        Ok(envelope)
+    }
+
+    pub fn decrypt(&self, envelope: &JsonValue) -> SpotifyResult<Vec<u8>> {
+        let salt = decode_field(envelope, "salt", None)?;
+        self.derive(&salt)?.open(envelope)
+    }
+}
+
+impl DerivedKey {
+    pub fn seal(&self, plaintext: &[u8]) -> SpotifyResult<JsonValue> {
+        let nonce = random_bytes(NONCE_LEN)?;
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.key));
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| Error::from("Failed to encrypt token cache"))?;
+
+        Ok(object!{
+            "algorithm" => ALGORITHM,
+            "nonce" => base64::encode(&nonce),
+            "ciphertext" => base64::encode(&ciphertext)
+        })
+    }
+
+    pub fn open(&self, envelope: &JsonValue) -> SpotifyResult<Vec<u8>> {
+        if envelope["algorithm"].as_str() != Some(ALGORITHM) {
+            bail!(ErrorKind::BadTokenCache(
+                String::from("unsupported encryption algorithm"),
+            ));
+        }
+
+        let nonce = decode_field(envelope, "nonce", Some(NONCE_LEN))?;
+        let ciphertext = decode_field(envelope, "ciphertext", None)?;
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.key));
+        cipher
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| ErrorKind::TokenCacheDecryptFailed.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use http::cachecrypt::*;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn encryption_round_trip() {
+        let enc = CacheEncryption::from_passphrase(String::from("hunter2"));
+        let envelope = enc.encrypt(b"secret token").unwrap();
+        assert!(CacheEncryption::is_envelope(&envelope));
+        assert!(!envelope.dump().contains("secret token"));
+        assert_eq!(enc.decrypt(&envelope).unwrap(), b"secret token");
+
+        // fresh salt and nonce every time
+        assert!(enc.encrypt(b"secret token").unwrap() != envelope);
+
+        match CacheEncryption::from_passphrase(String::from("hunter3")).decrypt(&envelope) {
+            Err(Error(ErrorKind::TokenCacheDecryptFailed, _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+    }
+
+    #[test]
+    fn key_file() {
+        let path = env::temp_dir().join("spotify-model-test-key-file");
+        File::create(&path).unwrap().write_all(b"").unwrap();
+        assert!(CacheEncryption::from_key_file(&path).is_err());
+
+        File::create(&path).unwrap().write_all(&[0, 1, 2, 3, 255]).unwrap();
+        let enc = CacheEncryption::from_key_file(&path).unwrap();
+        assert_eq!(enc.decrypt(&enc.encrypt(b"token").unwrap()).unwrap(), b"token");
+        assert_eq!(format!("{:?}", enc), "CacheEncryption { .. }");
+
+        fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,230 @@
+//! The Web API endpoints we know how to call, one entry each. An entry gives the path, which may
+//! contain `{name}` placeholders, the method, any fixed query, whether it needs a user, the
+//! scopes a token needs, how results are paged, and how many ids can be sent at once. See
+//! https://developer.spotify.com/web-api/endpoint-reference/
+
+use reqwest::{Method, Url};
+use url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+use error::*;
+use http::endpoints::Endpoints;
+use http::scope::{Scope, Scopes};
+
+/// How an endpoint splits its results across requests
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Pagination {
+    /// Everything comes back at once
+    None,
+    /// Pages are selected by `offset` and `limit`
+    Offset,
+    /// Pages follow on from a cursor, so there's no jumping ahead
+    Cursor,
+}
+
+#[derive(Debug)]
+pub struct ApiEndpoint {
+    pub name: &'static str,
+    pub method: Method,
+    /// Relative to the API base url, such as `albums/{id}/tracks`
+    pub path: &'static str,
+    /// Always sent, such as the kind of item to list
+    pub query: &'static [(&'static str, &'static str)],
+    /// Acts on behalf of a user, so can't be called with an app-only token
+    pub user_scoped: bool,
+    pub scopes: &'static [Scope],
+    pub pagination: Pagination,
+    /// How many ids can be sent in one request, for those taking a list of them
+    pub batch_limit: Option<usize>,
+}
+
+macro_rules! user_scoped {
+    (user) => { true };
+    (public) => { false };
+}
+
+/// Each entry is `NAME = METHOD "path", [query], user or public, [scopes], pagination, batch;`
+macro_rules! catalogue {
+    ($(
+        $name:ident = $method:ident $path:expr, [$($query:expr),*], $access:ident,
+            [$($scope:ident),*], $pagination:ident, $batch:expr;
+    )*) => {
+        $(
+            pub static $name: ApiEndpoint = ApiEndpoint {
+                name: stringify!($name),
+                method: Method::$method,
+                path: $path,
+                query: &[$($query),*],
+                user_scoped: user_scoped!($access),
+                scopes: &[$(Scope::$scope),*],
+                pagination: Pagination::$pagination,
+                batch_limit: $batch,
+            };
+        )*
+
+        /// Every endpoint in the catalogue
+        pub static CATALOGUE: &[&ApiEndpoint] = &[$(&$name),*];
+    };
+}
+
+catalogue! {
+    ME = Get "me", [], user, [], None, None;
+    SAVED_TRACKS = Get "me/tracks", [], user, [UserLibraryRead], Offset, None;
+    SAVE_TRACKS = Put "me/tracks", [], user, [UserLibraryModify], None, Some(50);
+    REMOVE_SAVED_TRACKS = Delete "me/tracks", [], user, [UserLibraryModify], None, Some(50);
+    SAVED_ALBUMS = Get "me/albums", [], user, [UserLibraryRead], Offset, None;
+    TOP_ARTISTS = Get "me/top/artists", [], user, [UserTopRead], Offset, None;
+    TOP_TRACKS = Get "me/top/tracks", [], user, [UserTopRead], Offset, None;
+    FOLLOWED_ARTISTS = Get "me/following", [("type", "artist")], user,
+        [UserFollowRead], Cursor, None;
+    RECENTLY_PLAYED = Get "me/player/recently-played", [], user,
+        [UserReadRecentlyPlayed], Cursor, None;
+    MY_PLAYLISTS = Get "me/playlists", [], user, [PlaylistReadPrivate], Offset, None;
+    ALBUM = Get "albums/{id}", [], public, [], None, None;
+    ALBUMS = Get "albums", [], public, [], None, Some(20);
+    ALBUM_TRACKS = Get "albums/{id}/tracks", [], public, [], Offset, None;
+    ARTIST = Get "artists/{id}", [], public, [], None, None;
+    ARTISTS = Get "artists", [], public, [], None, Some(50);
+    ARTIST_ALBUMS = Get "artists/{id}/albums", [], public, [], Offset, None;
+    TRACK = Get "tracks/{id}", [], public, [], None, None;
+    TRACKS = Get "tracks", [], public, [], None, Some(50);
+    // private playlists can only be read and changed with the private scopes
+    PLAYLIST_TRACKS = Get "playlists/{id}/tracks", [], user, [PlaylistReadPrivate], Offset, None;
+    ADD_PLAYLIST_TRACKS = Post "playlists/{id}/tracks", [], user,
+        [PlaylistModifyPublic, PlaylistModifyPrivate], None, Some(100);
+}
+
+fn misuse<S: Into<String>>(endpoint: &ApiEndpoint, reason: S) -> Error {
+    ErrorKind::BadEndpointUse(endpoint.name, reason.into()).into()
+}
+
+impl ApiEndpoint {
+    /// Whether the endpoint acts on behalf of a user, rather than reading the public catalog
+    #[inline]
+    pub fn is_user_scoped(&self) -> bool {
+        self.user_scoped
+    }
+
+    /// The scopes a token needs to be granted to use the endpoint
+    pub fn scopes(&self) -> Scopes {
+        Scopes::from(self.scopes)
+    }
+
+    /// The names of the placeholders in the path, in order
+    pub fn placeholders(&self) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        let mut rest = self.path;
+        while let Some(start) = rest.find('{') {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => break,
+            };
+            names.push(&rest[start + 1..end]);
+            rest = &rest[end + 1..];
+        }
+        names
+    }
+
+    /// The full url, with each placeholder filled in from `args` by name, and the fixed query
+    pub fn url(&self, endpoints: &Endpoints, args: &[(&str, &str)]) -> SpotifyResult<Url> {
+        let mut path = self.path.to_owned();
+        for name in self.placeholders() {
+            let value = match args.iter().find(|a| a.0 == name) {
+                Some(&(_, value)) if !value.is_empty() => value,
+                _ => bail!(misuse(self, format!("missing '{}'", name))),
+            };
+            let encoded = utf8_percent_encode(value, PATH_SEGMENT_ENCODE_SET).to_string();
+            path = path.replacen(&format!("{{{}}}", name), &encoded, 1);
+        }
+
+        if let Some(&(name, _)) = args.iter().find(|a| !self.placeholders().contains(&a.0)) {
+            bail!(misuse(self, format!("unexpected '{}'", name)));
+        }
+
+        let mut url = endpoints.api_url(&path).chain_err(|| "Failed to parse uri")?;
+        if !self.query.is_empty() {
+            url.query_pairs_mut().extend_pairs(self.query);
+        }
+        Ok(url)
+    }
+
+    /// How many ids can be sent at once, for endpoints that take a list of them
+    pub fn batch_limit(&self) -> SpotifyResult<usize> {
+        match self.batch_limit {
+            Some(limit) if limit > 0 => Ok(limit),
+            _ => Err(misuse(self, "it doesn't take a list of ids")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use http::catalogue::*;
+
+    #[test]
+    fn entries() {
+        let mut names: Vec<_> = CATALOGUE.iter().map(|e| e.name).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), CATALOGUE.len());
+
+        for endpoint in CATALOGUE {
+            // templates are balanced, and paths are relative to the API base
+            assert_eq!(endpoint.path.matches('{').count(), endpoint.placeholders().len());
+            assert_eq!(endpoint.path.matches('}').count(), endpoint.placeholders().len());
+            assert!(!endpoint.path.starts_with('/'), "{}", endpoint.name);
+            assert!(!endpoint.path.contains('?'), "{}", endpoint.name);
+
+            // scopes are only granted by a user
+            if !endpoint.scopes.is_empty() || endpoint.path.starts_with("me") {
+                assert!(endpoint.user_scoped, "{}", endpoint.name);
+            }
+
+            if let Some(limit) = endpoint.batch_limit {
+                assert!(limit > 0, "{}", endpoint.name);
+                assert_eq!(endpoint.pagination, Pagination::None, "{}", endpoint.name);
+            }
+        }
+    }
+
+    #[test]
+    fn urls() {
+        let endpoints = Endpoints::default();
+        let url = ALBUM_TRACKS.url(&endpoints, &[("id", "4aawyAB9vmqN3uQ7FjRGTy")]).unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://api.spotify.com/v1/albums/4aawyAB9vmqN3uQ7FjRGTy/tracks"
+        );
+
+        // values can't escape their segment
+        let url = ARTIST.url(&endpoints, &[("id", "../me?x=1")]).unwrap();
+        assert_eq!(url.path(), "/v1/artists/..%2Fme%3Fx=1");
+
+        let url = FOLLOWED_ARTISTS.url(&endpoints, &[]).unwrap();
+        assert_eq!(url.query(), Some("type=artist"));
+
+        for args in &[&[][..], &[("id", "")][..], &[("id", "a"), ("other", "b")][..]] {
+            match ALBUM_TRACKS.url(&endpoints, args) {
+                Err(Error(ErrorKind::BadEndpointUse("ALBUM_TRACKS", _), _)) => (),
+                _ => assert!(false, "Error not returned"),
+            }
+        }
+    }
+
+    #[test]
+    fn properties() {
+        assert!(SAVED_TRACKS.is_user_scoped());
+        assert!(ME.is_user_scoped());
+        assert!(!ALBUMS.is_user_scoped());
+        assert!(PLAYLIST_TRACKS.is_user_scoped());
+        assert!(PLAYLIST_TRACKS.scopes().contains(Scope::PlaylistReadPrivate));
+        assert!(ADD_PLAYLIST_TRACKS.scopes().contains(Scope::PlaylistModifyPrivate));
+        assert!(SAVE_TRACKS.scopes().contains(Scope::UserLibraryModify));
+        assert!(ARTISTS.scopes().is_empty());
+
+        assert_eq!(ALBUMS.batch_limit().unwrap(), 20);
+        match SAVED_TRACKS.batch_limit() {
+            Err(Error(ErrorKind::BadEndpointUse("SAVED_TRACKS", _), _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Where the accounts service and the web API are found, so that everything can be pointed at a
+//! local stand-in or a recording proxy instead of spotify.com
+
+use reqwest::Url;
+use error::*;
+
+const ACCOUNTS_URL: &str = "https://accounts.spotify.com/";
+const API_URL: &str = "https://api.spotify.com/v1/";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoints {
+    /// Serves `authorize` and `api/token`
+    pub accounts: Url,
+    /// The versioned root of the web API, such as `https://api.spotify.com/v1/`
+    pub api: Url,
+}
+
+/// Urls are joined relative to the base, so it needs a trailing slash to keep its last segment
+fn as_base(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    url
+}
+
+impl Endpoints {
+    pub fn new(accounts: Url, api: Url) -> Self {
+        Endpoints {
+            accounts: as_base(accounts),
+            api: as_base(api),
+        }
+    }
+
+    /// A single stand-in server for both, with the API under `/v1/` as on spotify.com
+    pub fn stand_in(base: &Url) -> SpotifyResult<Self> {
+        Ok(Endpoints::new(base.clone(), base.join("v1/")?))
+    }
+
+    /// The default endpoints, with either overridden
+    pub fn parse(accounts: Option<&str>, api: Option<&str>) -> SpotifyResult<Self> {
+        Ok(Endpoints::new(
+            Url::parse(accounts.unwrap_or(ACCOUNTS_URL))
+                .chain_err(|| "Invalid accounts url")?,
+            Url::parse(api.unwrap_or(API_URL)).chain_err(|| "Invalid API url")?,
+        ))
+    }
+
+    pub fn accounts_url(&self, path: &str) -> SpotifyResult<Url> {
+        Ok(self.accounts.join(path)?)
+    }
+
+    /// `path` is relative, such as `me/tracks`
+    pub fn api_url(&self, path: &str) -> SpotifyResult<Url> {
+        Ok(self.api.join(path)?)
+    }
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Endpoints::parse(None, None).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use http::endpoints::*;
+
+    #[test]
+    fn endpoint_urls() {
+        let endpoints = Endpoints::default();
+        assert_eq!(
+            endpoints.accounts_url("api/token").unwrap().as_str(),
+            "https://accounts.spotify.com/api/token"
+        );
+        assert_eq!(
+            endpoints.api_url("me/tracks").unwrap().as_str(),
+            "https://api.spotify.com/v1/me/tracks"
+        );
+
+        // a missing trailing slash doesn't lose the version
+        let endpoints = Endpoints::parse(None, Some("http://127.0.0.1:8080/proxy/v1")).unwrap();
+        assert_eq!(
+            endpoints.api_url("albums").unwrap().as_str(),
+            "http://127.0.0.1:8080/proxy/v1/albums"
+        );
+
+        let endpoints = Endpoints::stand_in(&Url::parse("http://127.0.0.1:1234").unwrap()).unwrap();
+        assert_eq!(endpoints.accounts.as_str(), "http://127.0.0.1:1234/");
+        assert_eq!(endpoints.api.as_str(), "http://127.0.0.1:1234/v1/");
+
+        assert!(Endpoints::parse(Some("not a url"), None).is_err());
+    }
+}
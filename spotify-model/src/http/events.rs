@@ -0,0 +1,71 @@
+//! Notifies front-ends of changes in the auth state as they happen, so they can show a login
+//! prompt or status without polling
+
+use std::fmt;
+
+use error::Error;
+use http::scope::Scopes;
+
+#[derive(Debug)]
+pub enum AuthEvent<'a> {
+    /// A valid token was found in the cache
+    TokenLoaded,
+    /// The token was renewed with a refresh token, without involving the user
+    TokenRefreshed,
+    /// The server rejected the token before it was due to expire
+    TokenRejected,
+    /// The user is about to be asked to authorise again
+    ReauthRequired(ReauthReason),
+    /// The user authorised us, and a new token was issued
+    LoggedIn,
+    /// Authorising failed, so there is no token
+    LoginFailed(&'a Error),
+    LoggedOut,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReauthReason {
+    /// There was no token, in memory or in the cache
+    NoToken,
+    /// An operation needs scopes the token wasn't granted, so these are asked for
+    MissingScopes(Scopes),
+    /// The token expired, and couldn't be refreshed
+    Expired,
+}
+
+/// Called on whichever thread caused the event, possibly while a token is being renewed, so
+/// must not ask `Auth` for a token itself
+pub trait AuthObserver: Send + Sync {
+    fn notify(&self, event: &AuthEvent);
+}
+
+impl<F> AuthObserver for F
+where
+    F: Fn(&AuthEvent) + Send + Sync,
+{
+    fn notify(&self, event: &AuthEvent) {
+        self(event)
+    }
+}
+
+#[derive(Default)]
+pub struct Observers(Vec<Box<dyn AuthObserver>>);
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Observers({})", self.0.len())
+    }
+}
+
+impl Observers {
+    pub fn add(&mut self, observer: Box<dyn AuthObserver>) {
+        self.0.push(observer);
+    }
+
+    pub fn emit(&self, event: AuthEvent) {
+        debug!("Auth event: {:?}", event);
+        for observer in &self.0 {
+            observer.notify(&event);
+        }
+    }
+}
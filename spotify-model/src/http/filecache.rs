@@ -0,0 +1,430 @@
+//! Persists the auth state between runs as a versioned JSON document. The line-based format
+//! used before versioning (token, expiry, then optionally refresh token and scopes) is still
+//! read, and rewritten in the current format
+//!
+//! The cache is only readable by its owner, replaced atomically, and guarded by an advisory lock
+//! so that concurrent processes don't race to renew the token. It can optionally be encrypted,
+//! in which case the document is wrapped in an envelope holding the ciphertext
+
+use std::path::{Path, PathBuf};
+use spotify;
+use error::*;
+use json::{self, JsonValue};
+use fs2::FileExt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::process;
+
+use http::auth::AuthState;
+use http::cachecrypt::CacheEncryption;
+use http::scope::{Scope, Scopes};
+
+const VERSION: u32 = 1;
+
+/// Used when no profile gives a path of its own
+pub fn default_path() -> PathBuf {
+    const STATE_FILE: &str = "state.conf";
+    let mut path = spotify::config_dir();
+    path.push(STATE_FILE);
+    path
+}
+
+/// Held while the cache is being read and renewed, and released on drop
+pub struct CacheLock {
+    file: File,
+}
+
+fn lock_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap().to_os_string();
+    name.push(".lock");
+    path.with_file_name(name)
+}
+
+fn owner_only(options: &mut OpenOptions) -> &mut OpenOptions {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+}
+
+fn bad_cache<S: Into<String>>(reason: S) -> Error {
+    ErrorKind::BadTokenCache(reason.into()).into()
+}
+
+/// Blocks until no other process is renewing the token
+pub fn lock_at(path: &Path) -> SpotifyResult<CacheLock> {
+    let file = owner_only(OpenOptions::new().write(true).create(true)).open(lock_path(path))?;
+    if file.try_lock_exclusive().is_err() {
+        debug!("Waiting for another process to finish with the token cache");
+        file.lock_exclusive()?;
+    }
+    Ok(CacheLock { file: file })
+}
+
+/// Deletes the cache, returning whether there was one. The lock file is left for whoever
+/// holds it
+pub fn remove(path: &Path) -> SpotifyResult<bool> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn save_to(
+    path: &Path,
+    state: &AuthState,
+    encryption: Option<&CacheEncryption>,
+) -> SpotifyResult<()> {
+    let mut doc = object!{
+        "version" => VERSION,
+        "token" => state.token.as_str(),
+        "token_type" => state.token_type.as_str(),
+        "expiry_time" => state.expiry_time,
+        "refresh_token" => state.refresh_token.as_deref(),
+        "scopes" => state.scopes.to_string(),
+        "account" => state.account.as_deref()
+    };
+
+    if let Some(encryption) = encryption {
+        doc = encryption.encrypt(doc.dump().as_bytes())?;
+        doc["version"] = VERSION.into();
+    }
+
+    write_private(path, doc.pretty(2).as_bytes())
+}
+
+/// Replaces the file atomically with one only its owner can read
+pub fn write_private(path: &Path, contents: &[u8]) -> SpotifyResult<()> {
+    // write to a temporary file first so readers never see a partial file
+    let tmp = path.with_extension(format!("tmp.{}", process::id()));
+    let result = write_then_rename(&tmp, path, contents);
+    if result.is_err() {
+        fs::remove_file(&tmp).ok();
+    }
+    result
+}
+
+fn write_then_rename(tmp: &Path, path: &Path, contents: &[u8]) -> SpotifyResult<()> {
+    let mut f = owner_only(OpenOptions::new().write(true).create(true).truncate(true))
+        .open(tmp)?;
+    f.write_all(contents)?;
+    f.sync_all()?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+pub fn load_from(path: &Path, encryption: Option<&CacheEncryption>) -> SpotifyResult<AuthState> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+
+    if contents.trim_start().starts_with('{') {
+        let doc = parse_json(&contents)?;
+        if !CacheEncryption::is_envelope(&doc) {
+            return parse(&doc);
+        }
+
+        let plaintext = match encryption {
+            Some(encryption) => encryption.decrypt(&doc)?,
+            None => bail!(ErrorKind::TokenCacheEncrypted),
+        };
+        let plaintext = String::from_utf8(plaintext).map_err(|_| {
+            bad_cache("decrypted cache is not utf8")
+        })?;
+        return parse(&parse_json(&plaintext)?);
+    }
+
+    let state = parse_legacy(&contents)?;
+    match save_to(path, &state, encryption) {
+        Ok(_) => debug!("Migrated legacy token cache to version {}", VERSION),
+        Err(e) => warn!("Failed to migrate legacy token cache: {}", e),
+    }
+    Ok(state)
+}
+
+fn optional_string(doc: &JsonValue, key: &str) -> SpotifyResult<Option<String>> {
+    match doc[key] {
+        JsonValue::Null => Ok(None),
+        ref v => {
+            v.as_str().map(|s| Some(s.to_owned())).ok_or_else(|| {
+                bad_cache(format!("{} is not a string", key))
+            })
+        }
+    }
+}
+
+/// Parses the document, checking it isn't from a newer version that we can't understand
+fn parse_json(contents: &str) -> SpotifyResult<JsonValue> {
+    let doc = json::parse(contents).map_err(|e| {
+        bad_cache(format!("not valid json ({})", e))
+    })?;
+
+    let version = doc["version"].as_u32().ok_or_else(|| {
+        bad_cache("version is missing")
+    })?;
+    if version > VERSION {
+        return Err(bad_cache(format!(
+            "version {} is newer than the supported version {}",
+            version,
+            VERSION
+        )));
+    }
+
+    Ok(doc)
+}
+
+fn parse(doc: &JsonValue) -> SpotifyResult<AuthState> {
+    Ok(AuthState {
+        token: doc["token"].as_str().map(|s| s.to_owned()).ok_or_else(|| {
+            bad_cache("token is missing")
+        })?,
+        token_type: doc["token_type"].as_str().map(|s| s.to_owned()).ok_or_else(
+            || bad_cache("token type is missing"),
+        )?,
+        expiry_time: doc["expiry_time"].as_i64().ok_or_else(|| {
+            bad_cache("expiry time is missing or not a number")
+        })?,
+        refresh_token: optional_string(doc, "refresh_token")?,
+        scopes: doc["scopes"].as_str().map(Scopes::parse).ok_or_else(|| {
+            bad_cache("scopes are missing")
+        })?,
+        account: optional_string(doc, "account")?,
+    })
+}
+
+fn parse_legacy(contents: &str) -> SpotifyResult<AuthState> {
+    let mut lines = contents.lines();
+
+    let token = match lines.next() {
+        Some(t) if !t.is_empty() => t.to_owned(),
+        _ => return Err(bad_cache("legacy token is missing")),
+    };
+    let expiry_time = lines
+        .next()
+        .ok_or_else(|| bad_cache("legacy expiry time is missing"))?
+        .parse()
+        .map_err(|_| bad_cache("legacy expiry time is not a number"))?;
+    let refresh_token = lines.next().and_then(|s| if s.is_empty() {
+        None
+    } else {
+        Some(s.to_owned())
+    });
+    let scopes = match lines.next() {
+        Some(s) => Scopes::parse(s),
+        // only ever granted the scope that used to be hardcoded
+        None => Scopes::from(&[Scope::UserLibraryRead][..]),
+    };
+
+    Ok(AuthState {
+        token: token,
+        token_type: String::from("Bearer"),
+        expiry_time: expiry_time,
+        refresh_token: refresh_token,
+        scopes: scopes,
+        account: None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use http::filecache::*;
+    use std::env;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("spotify-model-test-{}", name))
+    }
+
+    fn write(path: &Path, contents: &str) {
+        File::create(path)
+            .unwrap()
+            .write_all(contents.as_bytes())
+            .unwrap();
+    }
+
+    fn expect_bad(contents: &str, expected: &str) {
+        match parse_json(contents).and_then(|doc| parse(&doc)) {
+            Err(Error(ErrorKind::BadTokenCache(ref reason), _)) => {
+                assert!(
+                    reason.contains(expected),
+                    "'{}' does not contain '{}'",
+                    reason,
+                    expected
+                )
+            }
+            _ => assert!(false, "Error not returned"),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temp_path("round-trip");
+        let state = AuthState {
+            token: String::from("token"),
+            token_type: String::from("Bearer"),
+            expiry_time: 1500000000,
+            refresh_token: Some(String::from("refresh")),
+            scopes: Scopes::from(&[Scope::UserLibraryRead, Scope::UserTopRead][..]),
+            account: Some(String::from("someone")),
+        };
+        save_to(&path, &state, None).unwrap();
+        let loaded = load_from(&path, None).unwrap();
+        assert_eq!(loaded.token, "token");
+        assert_eq!(loaded.token_type, "Bearer");
+        assert_eq!(loaded.expiry_time, 1500000000);
+        assert_eq!(loaded.refresh_token, Some(String::from("refresh")));
+        assert_eq!(loaded.scopes, state.scopes);
+        assert_eq!(loaded.account, Some(String::from("someone")));
+
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn encrypted_cache() {
+        let path = temp_path("encrypted");
+        let encryption = CacheEncryption::from_passphrase(String::from("hunter2"));
+        let state = parse_legacy("token\n1500000000\nrefresh").unwrap();
+
+        save_to(&path, &state, Some(&encryption)).unwrap();
+        let mut contents = String::new();
+        File::open(&path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert!(!contents.contains("refresh"));
+
+        let loaded = load_from(&path, Some(&encryption)).unwrap();
+        assert_eq!(loaded.token, "token");
+        assert_eq!(loaded.refresh_token, Some(String::from("refresh")));
+
+        match load_from(&path, None) {
+            Err(Error(ErrorKind::TokenCacheEncrypted, _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+
+        let wrong = CacheEncryption::from_passphrase(String::from("hunter3"));
+        match load_from(&path, Some(&wrong)) {
+            Err(Error(ErrorKind::TokenCacheDecryptFailed, _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+
+        // a plain cache is still readable after turning encryption on
+        save_to(&path, &state, None).unwrap();
+        assert_eq!(load_from(&path, Some(&encryption)).unwrap().token, "token");
+
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn atomic_private_writes() {
+        let path = temp_path("private");
+        write(&path, "old contents");
+
+        let state = parse_legacy("token\n1500000000").unwrap();
+        save_to(&path, &state, None).unwrap();
+        assert_eq!(load_from(&path, None).unwrap().token, "token");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            // the mode of the replaced file must not leak through
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let leftovers = fs::read_dir(env::temp_dir())
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| {
+                e.file_name().to_string_lossy().starts_with(
+                    "spotify-model-test-private.tmp",
+                )
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+
+        assert!(remove(&path).unwrap());
+        assert!(!path.exists());
+        assert!(!remove(&path).unwrap());
+    }
+
+    #[test]
+    fn lock_waits() {
+        let path = temp_path("lock");
+        let released = Arc::new(AtomicBool::new(false));
+
+        let lock = lock_at(&path).unwrap();
+        let waiter = {
+            let path = path.clone();
+            let released = released.clone();
+            thread::spawn(move || {
+                let _lock = lock_at(&path).unwrap();
+                released.load(Ordering::SeqCst)
+            })
+        };
+
+        thread::sleep(Duration::from_millis(100));
+        released.store(true, Ordering::SeqCst);
+        drop(lock);
+
+        assert!(waiter.join().unwrap(), "lock was taken while held");
+        fs::remove_file(lock_path(&path)).ok();
+    }
+
+    #[test]
+    fn legacy_migration() {
+        let path = temp_path("legacy");
+        write(&path, "token\n1500000000");
+
+        let loaded = load_from(&path, None).unwrap();
+        assert_eq!(loaded.token, "token");
+        assert_eq!(loaded.expiry_time, 1500000000);
+        assert_eq!(loaded.refresh_token, None);
+        assert_eq!(loaded.scopes, Scopes::from(&[Scope::UserLibraryRead][..]));
+
+        // rewritten in the current format
+        let mut contents = String::new();
+        File::open(&path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(json::parse(&contents).unwrap()["version"], VERSION);
+
+        let legacy = parse_legacy("token\n1500000000\nrefresh\nuser-top-read").unwrap();
+        assert_eq!(legacy.refresh_token, Some(String::from("refresh")));
+        assert_eq!(legacy.scopes, Scopes::from(&[Scope::UserTopRead][..]));
+
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn corrupt_caches() {
+        expect_bad("{ nope", "not valid json");
+        expect_bad(r#"{"token": "t"}"#, "version is missing");
+        expect_bad(r#"{"version": 2}"#, "version 2 is newer than the supported version 1");
+        expect_bad(r#"{"version": 1}"#, "token is missing");
+        expect_bad(
+            r#"{"version": 1, "token": "t", "token_type": "Bearer", "expiry_time": "soon"}"#,
+            "expiry time",
+        );
+        expect_bad(
+            r#"{"version": 1, "token": "t", "token_type": "Bearer", "expiry_time": 1,
+                "refresh_token": 5, "scopes": ""}"#,
+            "refresh_token is not a string",
+        );
+
+        match parse_legacy("token\nsoon") {
+            Err(Error(ErrorKind::BadTokenCache(ref reason), _)) => {
+                assert_eq!(reason, "legacy expiry time is not a number")
+            }
+            _ => assert!(false, "Error not returned"),
+        }
+        assert!(parse_legacy("").is_err());
+        assert!(parse_legacy("token").is_err());
+    }
+}
@@ -1,2 +1,15 @@
 pub mod request;
 pub mod auth;
+pub mod scope;
+mod filecache;
+pub mod cachecrypt;
+pub mod catalogue;
+pub mod events;
+pub mod endpoints;
+pub mod params;
+pub mod ratelimit;
+pub mod responsecache;
+pub mod retry;
+
+#[cfg(test)]
+pub mod testserver;
@@ -0,0 +1,141 @@
+//! Parsing of the key-value lists in URL queries and fragments. Keys are matched exactly rather
+//! than by searching for a substring, so `token` is never found inside `access_token`
+
+use url::form_urlencoded;
+
+/// Percent-decoded `key=value` pairs, in the order they appeared
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Params(Vec<(String, String)>);
+
+/// The query and fragment of a redirect URL, which may carry the same keys
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RedirectParams {
+    pub query: Params,
+    pub fragment: Params,
+}
+
+impl Params {
+    /// Parses `a=1&b=2`, ignoring a leading `?` or `#`. A key without `=` has an empty value
+    pub fn parse(s: &str) -> Self {
+        let s = s.trim_start_matches(['?', '#']);
+        Params(
+            form_urlencoded::parse(s.as_bytes())
+                .into_owned()
+                .collect(),
+        )
+    }
+
+    /// The value of the first occurrence of `key`
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.iter().find(|p| p.0 == key).map(|p| p.1.as_str())
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl RedirectParams {
+    /// Accepts a full URL, or just its query string or fragment, such as when pasted by a user
+    pub fn parse(s: &str) -> Self {
+        let s = s.trim();
+        let (before, fragment) = match s.find('#') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => (s, ""),
+        };
+
+        let query = match before.find('?') {
+            Some(i) => &before[i + 1..],
+            // a bare URL has no query, but a bare query is all query
+            None if before.contains("://") => "",
+            None => before,
+        };
+
+        RedirectParams {
+            query: Params::parse(query),
+            fragment: Params::parse(fragment),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use http::params::*;
+
+    #[test]
+    fn exact_keys() {
+        let params = Params::parse("access_token=abc&token_type=Bearer&expires_in=3600");
+        assert_eq!(params.get("access_token"), Some("abc"));
+        assert_eq!(params.get("token"), None);
+        assert_eq!(params.get("type"), None);
+        assert_eq!(params.get("expires_in"), Some("3600"));
+        assert_eq!(params.get("expires"), None);
+
+        // a key that is the suffix of an earlier value
+        let params = Params::parse("error_description=bad_state&state=xyz");
+        assert_eq!(params.get("state"), Some("xyz"));
+        assert_eq!(Params::parse("x=state%3Dforged").get("state"), None);
+    }
+
+    #[test]
+    fn decoding() {
+        let params =
+            Params::parse("?state=a%2Fb%3Dc&name=hello+world&emoji=%F0%9F%8E%B5&pct=100%25");
+        assert_eq!(params.get("state"), Some("a/b=c"));
+        assert_eq!(params.get("name"), Some("hello world"));
+        assert_eq!(params.get("emoji"), Some("\u{1f3b5}"));
+        assert_eq!(params.get("pct"), Some("100%"));
+
+        // keys are decoded too, and malformed escapes are kept as they are
+        assert_eq!(Params::parse("a%5Fb=1").get("a_b"), Some("1"));
+        assert_eq!(Params::parse("bad=%zz%4").get("bad"), Some("%zz%4"));
+    }
+
+    #[test]
+    fn odd_lists() {
+        assert!(Params::parse("").is_empty());
+        assert!(Params::parse("#").is_empty());
+        assert!(Params::parse("&&&").is_empty());
+
+        let params = Params::parse("&empty=&flag&trailing=1&");
+        assert_eq!(params.get("empty"), Some(""));
+        assert_eq!(params.get("flag"), Some(""));
+        assert!(params.contains("flag"));
+        assert_eq!(params.get("trailing"), Some("1"));
+        assert_eq!(params.get(""), None);
+
+        // the first of repeated keys wins
+        assert_eq!(Params::parse("code=first&code=second").get("code"), Some("first"));
+
+        // only the first = separates
+        assert_eq!(Params::parse("k=a=b").get("k"), Some("a=b"));
+    }
+
+    #[test]
+    fn redirect_urls() {
+        let r = RedirectParams::parse("http://127.0.0.1:8888/callback?code=abc&state=xyz");
+        assert_eq!(r.query.get("code"), Some("abc"));
+        assert!(r.fragment.is_empty());
+
+        let r = RedirectParams::parse("http://localhost/cb#access_token=t&state=s");
+        assert!(r.query.is_empty());
+        assert_eq!(r.fragment.get("access_token"), Some("t"));
+
+        // the fragment is not part of the query
+        let r = RedirectParams::parse("http://localhost/cb?code=a#code=b");
+        assert_eq!(r.query.get("code"), Some("a"));
+        assert_eq!(r.fragment.get("code"), Some("b"));
+
+        // pasted without the address
+        assert_eq!(RedirectParams::parse("  ?code=abc\n").query.get("code"), Some("abc"));
+        assert_eq!(RedirectParams::parse("code=abc").query.get("code"), Some("abc"));
+        assert_eq!(RedirectParams::parse("#state=s").fragment.get("state"), Some("s"));
+
+        assert!(RedirectParams::parse("http://localhost/cb").query.is_empty());
+        assert_eq!(RedirectParams::parse(""), RedirectParams::default());
+    }
+}
@@ -0,0 +1,150 @@
+//! Spaces out API requests so that bulk operations stay under Spotify's rate limit, rather than
+//! running into it and waiting out a `429 Too Many Requests`. One limiter is shared by every
+//! request made through the same `Auth`, from any thread
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Requests that can be made at once after a quiet spell
+pub const DEFAULT_BURST: u32 = 20;
+
+/// The sustained rate, which Spotify doesn't publish but comfortably allows
+pub const DEFAULT_PER_SECOND: f64 = 10.0;
+
+/// A token bucket, which also holds every request back while the server has asked us to wait
+#[derive(Debug)]
+pub struct RateLimiter {
+    burst: f64,
+    per_second: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    paused_until: Option<Instant>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(DEFAULT_BURST, DEFAULT_PER_SECOND)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(burst: u32, per_second: f64) -> Self {
+        let burst = f64::from(burst.max(1));
+        RateLimiter {
+            burst: burst,
+            per_second: per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                refilled_at: Instant::now(),
+                paused_until: None,
+            }),
+        }
+    }
+
+    /// Never holds a request back, other than when the server asks us to wait
+    pub fn unlimited() -> Self {
+        RateLimiter::new(1, f64::INFINITY)
+    }
+
+    /// Blocks until a request may be made
+    pub fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now = Instant::now();
+
+                match bucket.paused_until {
+                    Some(until) if until > now => until - now,
+                    _ => {
+                        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+                        bucket.tokens = if self.per_second.is_infinite() {
+                            self.burst
+                        } else {
+                            (bucket.tokens + elapsed * self.per_second).min(self.burst)
+                        };
+                        bucket.refilled_at = now;
+                        bucket.paused_until = None;
+
+                        if bucket.tokens >= 1.0 {
+                            bucket.tokens -= 1.0;
+                            return;
+                        }
+                        Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_second)
+                    }
+                }
+            };
+
+            trace!("Waiting {:?} to stay under the rate limit", wait);
+            thread::sleep(wait);
+        }
+    }
+
+    /// Holds back every request for `wait`, such as when told to by `Retry-After`
+    pub fn pause(&self, wait: Duration) {
+        let mut bucket = self.bucket.lock().unwrap();
+        let until = Instant::now() + wait;
+        if bucket.paused_until.map(|t| t < until).unwrap_or(true) {
+            bucket.paused_until = Some(until);
+        }
+        // a burst straight after the pause would likely be limited again
+        bucket.tokens = bucket.tokens.min(1.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use http::ratelimit::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn token_bucket() {
+        let limiter = RateLimiter::new(3, 50.0);
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.acquire();
+        }
+        assert!(start.elapsed() < Duration::from_millis(15));
+
+        // the rest are spaced out, across threads too
+        let limiter = Arc::new(limiter);
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let limiter = limiter.clone();
+                thread::spawn(move || for _ in 0..5 {
+                    limiter.acquire();
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(190));
+
+        let limiter = RateLimiter::unlimited();
+        let start = Instant::now();
+        for _ in 0..1000 {
+            limiter.acquire();
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn paused() {
+        let limiter = RateLimiter::unlimited();
+        limiter.pause(Duration::from_millis(100));
+        // a shorter pause doesn't cut a longer one short
+        limiter.pause(Duration::from_millis(10));
+
+        let start = Instant::now();
+        limiter.acquire();
+        assert!(start.elapsed() >= Duration::from_millis(90));
+        limiter.acquire();
+        assert!(start.elapsed() < Duration::from_millis(150));
+    }
+}
@@ -1,65 +1,252 @@
-use reqwest::Url;
-use reqwest::header::{Authorization, Bearer};
+use reqwest::{Method, Response, StatusCode, Url};
+use reqwest::header::{Authorization, Bearer, ContentType, Headers};
 use error::*;
 use json::{parse, JsonValue};
 
 use std::io::Read;
+use std::thread;
+use std::time::Duration;
 use std::slice::Chunks;
 
 use http::auth::*;
+use http::scope::Scopes;
+use http::catalogue::{ApiEndpoint, Pagination};
+use http::responsecache::ResponseCache;
 
-#[derive(Debug, Copy, Clone)]
-pub enum ApiEndpoint {
-    SavedTracks,
-    Albums,
-    Artists,
+/// Fails early with a clear error if the endpoint can't be reached with our authorisation
+fn check_access(auth: &Auth, endpoint: &ApiEndpoint) -> SpotifyResult<()> {
+    if endpoint.is_user_scoped() && auth.mode() == AuthMode::App {
+        bail!(ErrorKind::UserRequired(endpoint.name));
+    }
+    Ok(())
 }
 
-fn get_uri_with_params(endpoint: ApiEndpoint, params: &[(&str, &str)]) -> SpotifyResult<Url> {
-    Url::parse_with_params(get_uri(endpoint), params).chain_err(|| "Failed to parse uri")
+/// The etag and body of the response cached for `url`, to revalidate. A body that's no longer
+/// json is dropped, so that the response is fetched afresh rather than confirmed unchanged
+fn revalidatable(cache: &ResponseCache, url: &str) -> Option<(String, JsonValue)> {
+    let cached = cache.get(url)?;
+    match parse(&cached.body) {
+        Ok(body) => Some((cached.etag, body)),
+        Err(e) => {
+            warn!("Dropping cached response that isn't json: {}", e);
+            if let Err(e) = cache.remove(url) {
+                warn!("Failed to remove cached response: {}", e);
+            }
+            None
+        }
+    }
 }
 
-fn get_uri(endpoint: ApiEndpoint) -> &'static str {
-    match endpoint {
-        ApiEndpoint::SavedTracks => "https://api.spotify.com/v1/me/tracks",
-        ApiEndpoint::Albums => "https://api.spotify.com/v1/albums",
-        ApiEndpoint::Artists => "https://api.spotify.com/v1/artists",
-    }
+/// Calls an endpoint from the catalogue, filling its path in from `args`
+pub fn call(
+    auth: &Auth,
+    endpoint: &ApiEndpoint,
+    args: &[(&str, &str)],
+    params: &[(&str, &str)],
+    body: Option<&JsonValue>,
+) -> SpotifyResult<JsonValue> {
+    check_access(auth, endpoint)?;
+    let url = endpoint.url(auth.endpoints(), args)?;
+    send_request(auth, endpoint.method.clone(), url, params, body, &endpoint.scopes())
+}
+
+/// Seconds to wait before retrying, from a `Retry-After` header. The date form isn't used by
+/// Spotify, so isn't supported
+pub fn retry_after(response: &Response) -> Option<u64> {
+    response
+        .headers()
+        .get_raw("Retry-After")
+        .and_then(|values| values.first())
+        .and_then(|v| String::from_utf8_lossy(v).trim().parse().ok())
+}
+
+/// Tries at a request that keeps being rate limited before giving up
+const RATE_LIMITED_ATTEMPTS: u32 = 5;
+
+/// Waits longer than this are given up on rather than blocking for ages
+const MAX_RETRY_AFTER_SECS: u64 = 120;
+
+/// For when a 429 doesn't say how long to wait
+const DEFAULT_RETRY_AFTER_SECS: u64 = 1;
+
+/// The `ETag` of a response, exactly as sent so that it can be sent back
+fn etag(response: &Response) -> Option<String> {
+    response
+        .headers()
+        .get_raw("ETag")
+        .and_then(|values| values.first())
+        .map(|v| String::from_utf8_lossy(v).into_owned())
+}
+
+pub fn send_api_request(auth: &Auth, url: Url, scopes: &Scopes) -> SpotifyResult<JsonValue> {
+    send_request(auth, Method::Get, url, &[], None, scopes)
 }
 
-pub fn send_api_request(auth: &Auth, url: Url) -> SpotifyResult<JsonValue> {
+/// Sends an authorised request with `params` added to the query, and `body` sent as JSON.
+/// A response without a body, such as `201 Created` or `204 No Content` from a write, is
+/// returned as null
+pub fn send_request(
+    auth: &Auth,
+    method: Method,
+    mut url: Url,
+    params: &[(&str, &str)],
+    body: Option<&JsonValue>,
+    scopes: &Scopes,
+) -> SpotifyResult<JsonValue> {
     // TODO avoid allocation with token
-    debug!("Sending HTTP request to {:?}", url);
+    if !params.is_empty() {
+        url.query_pairs_mut().extend_pairs(params);
+    }
+    // the query can be long, and the log filter masks anything sensitive in it
+    debug!("Sending HTTP {} request to {}", method, url.path());
+    trace!("Full request URL is {}", url);
     let client = auth.client();
-    let mut response = client
-        .get(url)
-        .header(Authorization(Bearer { token: auth.token(client)? }))
-        .send()?;
+
+    // only reads are cached, and a write may have been applied even if the connection was
+    // then lost or a gateway gave up waiting, so it's not repeated unless it would have the
+    // same effect again
+    let is_read = method == Method::Get;
+    let idempotent = matches!(method, Method::Get | Method::Head | Method::Put | Method::Delete);
+    let cache = if is_read { auth.response_cache() } else { None };
+    let cached = cache.and_then(|c| revalidatable(c, url.as_str()));
+    let body = body.map(|b| b.dump());
+
+    let send_once = || -> SpotifyResult<(String, Response)> {
+        let token = auth.token_with_scopes(scopes)?;
+        let policy = auth.retry_policy();
+        let mut failures = 0;
+        loop {
+            auth.rate_limiter().acquire();
+            let mut headers = Headers::new();
+            if let Some((ref etag, _)) = cached {
+                headers.set_raw("If-None-Match", vec![etag.clone().into_bytes()]);
+            }
+            let mut request = client
+                .request(method.clone(), url.clone())
+                .header(Authorization(Bearer { token: token.clone() }))
+                .headers(headers);
+            match body {
+                Some(ref body) => request = request.header(ContentType::json()).body(body.clone()),
+                // writes without a body still need a length of zero
+                None if !is_read => request = request.body(""),
+                None => (),
+            }
+            let result = request.send();
+
+            // transient failures are tried again after a growing delay
+            let retry = match result {
+                Err(ref e) if idempotent && policy.retries_error(e) => format!("{}", e),
+                Ok(ref r) if idempotent && policy.retries_status(*r.status()) => {
+                    format!("{:?}", r.status())
+                }
+                _ => return Ok((token, result?)),
+            };
+            failures += 1;
+            if failures >= policy.max_attempts() {
+                debug!("Giving up on request after {} attempts", failures);
+                return Ok((token, result?));
+            }
+
+            let delay = policy.delay(failures);
+            warn!("Request failed ({}), trying again in {:?}", retry, delay);
+            thread::sleep(delay);
+        }
+    };
+
+    // waits out the rate limit as the server asks, holding back other requests too
+    let send = || -> SpotifyResult<(String, Response)> {
+        let mut attempts = 0;
+        loop {
+            let (token, response) = send_once()?;
+            if *response.status() != StatusCode::TooManyRequests {
+                return Ok((token, response));
+            }
+
+            attempts += 1;
+            let wait = retry_after(&response);
+            if attempts >= RATE_LIMITED_ATTEMPTS || wait.unwrap_or(0) > MAX_RETRY_AFTER_SECS {
+                bail!(ErrorKind::RateLimited(wait));
+            }
+
+            let wait = wait.unwrap_or(DEFAULT_RETRY_AFTER_SECS);
+            warn!("Rate limited, waiting {}s before trying again", wait);
+            auth.rate_limiter().pause(Duration::from_secs(wait));
+        }
+    };
+
+    let (token, mut response) = send()?;
+    if *response.status() == StatusCode::Unauthorized {
+        // revoked, or expired sooner than we expected, so authorise again once
+        auth.invalidate(&token);
+        response = send()?.1;
+
+        if *response.status() == StatusCode::Unauthorized {
+            bail!(ErrorKind::Unauthorized);
+        }
+    }
+
+    if *response.status() == StatusCode::Forbidden {
+        // not always for want of a scope, such as a player endpoint with a free account, so
+        // Spotify's reason is kept
+        let mut raw = String::new();
+        response.read_to_string(&mut raw)?;
+        let message = parse(&raw)
+            .ok()
+            .and_then(|doc| doc["error"]["message"].as_str().map(|s| s.to_owned()))
+            .unwrap_or_else(|| String::from("no reason given"));
+        let granted = auth.state
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|s| s.scopes.clone())
+            .unwrap_or_default();
+        bail!(ErrorKind::Forbidden(message, scopes.clone(), granted));
+    }
+
+    if let (&StatusCode::NotModified, Some((_, body))) = (response.status(), cached) {
+        trace!("Using cached response for {}", url.path());
+        return Ok(body);
+    }
 
     if !response.status().is_success() {
         bail!(ErrorKind::BadResponseStatusCode(*response.status()));
     }
 
-    // TODO use etag header for caching
-    // https://developer.spotify.com/web-api/user-guide/#conditional-requests
-
     let mut raw = String::new();
     response.read_to_string(&mut raw)?;
-    Ok(parse(&raw).unwrap())
+    if raw.trim().is_empty() {
+        return Ok(JsonValue::Null);
+    }
+    let parsed = parse(&raw).chain_err(|| "Response is not json")?;
+
+    if let (Some(cache), Some(etag)) = (cache, etag(&response)) {
+        if let Err(e) = cache.put(url.as_str(), &etag, &raw) {
+            warn!("Failed to cache response: {}", e);
+        }
+    }
+    Ok(parsed)
 }
 
+/// Looks up several items by id, in batches. A failed batch is yielded as an error, after
+/// which the iterator ends
 pub struct SeveralIterator<'a> {
     auth: &'a Auth,
-    endpoint: ApiEndpoint,
+    endpoint: &'a ApiEndpoint,
     limit: usize,
     buffer: Vec<JsonValue>,
     in_vec: &'a [String],
     in_chunks: Chunks<'a, String>,
+    failed: bool,
 }
 
 impl<'a> SeveralIterator<'a> {
-    pub fn new(auth: &'a Auth, endpoint: ApiEndpoint, what: &'a [String]) -> SpotifyResult<Self> {
-        let limit = SeveralIterator::get_limit(endpoint);
+    pub fn new(
+        auth: &'a Auth,
+        endpoint: &'a ApiEndpoint,
+        what: &'a [String],
+    ) -> SpotifyResult<Self> {
+        check_access(auth, endpoint)?;
+        let limit = endpoint.batch_limit()?;
         let it = SeveralIterator {
             auth: auth,
             endpoint: endpoint,
@@ -67,6 +254,7 @@ impl<'a> SeveralIterator<'a> {
             buffer: Vec::with_capacity(limit),
             in_vec: what,
             in_chunks: what.chunks(limit),
+            failed: false,
         };
         Ok(it)
     }
@@ -74,18 +262,9 @@ impl<'a> SeveralIterator<'a> {
     fn fetch(&mut self) -> SpotifyResult<()> {
         // init chunks because it's apparently impossible to do in the constructor
         if let Some(ids) = self.in_chunks.next() {
-            let url = {
-                // repeated parameters not supported!
-                let uri = get_uri(self.endpoint);
-                let joined = ids.join(",");
-                let prefix = "?ids=";
-                let mut qs = String::with_capacity(uri.len() + prefix.len() + joined.len());
-                qs.push_str(uri);
-                qs.push_str(prefix);
-                qs.push_str(&joined);
-                Url::parse(&qs)?
-            };
-            let mut response = send_api_request(self.auth, url)?;
+            // repeated parameters not supported!
+            let ids = ids.join(",");
+            let mut response = call(self.auth, self.endpoint, &[], &[("ids", &ids)], None)?;
             if let JsonValue::Object(mut obj) = response.take() {
                 let mut arr = obj.iter_mut()
                     .map(|(_k, mut v)| v.take())
@@ -99,54 +278,67 @@ impl<'a> SeveralIterator<'a> {
         }
         Ok(())
     }
-
-    fn get_limit(endpoint: ApiEndpoint) -> usize {
-        match endpoint {
-            ApiEndpoint::Albums => 20,
-            ApiEndpoint::Artists => 50,
-            _ => 0,
-        }
-    }
 }
 
 impl<'a> Iterator for SeveralIterator<'a> {
-    type Item = JsonValue;
+    type Item = SpotifyResult<JsonValue>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.buffer.pop().or_else(|| match self.fetch() {
+        if self.failed {
+            return None;
+        }
+
+        self.buffer.pop().map(Ok).or_else(|| match self.fetch() {
             Err(e) => {
-                warn!("Failed to get next in iterator: {:?}", e);
-                None
+                self.failed = true;
+                Some(Err(e))
             }
-            _ => self.buffer.pop(),
+            _ => self.buffer.pop().map(Ok),
         })
     }
 }
 
+/// Follows the pages of a paginated endpoint. A failed page is yielded as an error, after
+/// which the iterator ends
 pub struct PageIterator<'a> {
     auth: &'a Auth,
-    endpoint: ApiEndpoint,
+    endpoint: &'a ApiEndpoint,
     limit: usize,
     total: u32,
     next: Option<Url>,
     buffer: Vec<JsonValue>,
+    failed: bool,
 }
 
 impl<'a> PageIterator<'a> {
-    pub fn new(auth: &'a Auth, endpoint: ApiEndpoint) -> SpotifyResult<Self> {
+    /// Starts from the first page of `endpoint`, with its path filled in from `args`
+    pub fn new(
+        auth: &'a Auth,
+        endpoint: &'a ApiEndpoint,
+        args: &[(&str, &str)],
+    ) -> SpotifyResult<Self> {
         const LIMIT: usize = 50;
         const LIMIT_STR: &str = "50"; // pff why not
 
+        check_access(auth, endpoint)?;
+        let params: &[(&str, &str)] = match endpoint.pagination {
+            Pagination::Offset => &[("limit", LIMIT_STR), ("offset", "0")],
+            Pagination::Cursor => &[("limit", LIMIT_STR)],
+            Pagination::None => {
+                bail!(ErrorKind::BadEndpointUse(endpoint.name, String::from("it isn't paged")))
+            }
+        };
+        let mut first = endpoint.url(auth.endpoints(), args)?;
+        first.query_pairs_mut().extend_pairs(params);
+
         let mut it = PageIterator {
             auth: auth,
             endpoint: endpoint,
             limit: LIMIT,
             total: 0,
-            next: Some({
-                let params = [("limit", LIMIT_STR), ("offset", "0")];
-                get_uri_with_params(endpoint, &params)?
-            }),
+            next: Some(first),
             buffer: Vec::with_capacity(LIMIT),
+            failed: false,
         };
 
         it.fetch()?;
@@ -154,21 +346,40 @@ impl<'a> PageIterator<'a> {
         Ok(it)
     }
 
+    /// How many items there are in all, as of the last page fetched
+    #[inline]
+    pub fn total(&self) -> u32 {
+        self.total
+    }
+
     fn fetch(&mut self) -> SpotifyResult<()> {
         let url = match self.next.take() {
             Some(s) => s,
             None => return Ok(()), // end reached
         };
 
-        let mut response = send_api_request(self.auth, url)?;
+        let mut response = send_api_request(self.auth, url, &self.endpoint.scopes())?;
+
+        // some cursor-paged endpoints wrap the page in an object named after what's in it
+        let mut page = if response.is_object() && !response.has_key("items") &&
+            response.len() == 1
+        {
+            response.entries_mut().next().map_or(JsonValue::Null, |(_, v)| v.take())
+        } else {
+            response
+        };
+        if !page.is_object() {
+            bail!(ErrorKind::BadResponse("page is not an object"));
+        }
 
         self.buffer.clear();
-        self.buffer.extend((response["items"]).members_mut().map(
+        self.buffer.extend((page["items"]).members_mut().map(
             |o| o.take(),
         ));
 
-        self.total = response["total"].as_u32().unwrap();
-        self.next = match response["next"] {
+        // not all cursor-paged endpoints give a total
+        self.total = page["total"].as_u32().unwrap_or(self.total);
+        self.next = match page["next"] {
             JsonValue::String(ref url) => Some(Url::parse(url)?),
             _ => None,
         };
@@ -183,15 +394,407 @@ impl<'a> PageIterator<'a> {
 }
 
 impl<'a> Iterator for PageIterator<'a> {
-    type Item = JsonValue;
+    type Item = SpotifyResult<JsonValue>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.buffer.pop().or_else(|| match self.fetch() {
+        if self.failed {
+            return None;
+        }
+
+        self.buffer.pop().map(Ok).or_else(|| match self.fetch() {
             Err(e) => {
-                warn!("Failed to get next in iterator: {:?}", e);
-                None
+                self.failed = true;
+                Some(Err(e))
             }
-            _ => self.buffer.pop(),
+            _ => self.buffer.pop().map(Ok),
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use http::request::*;
+    use http::catalogue;
+    use http::testserver::{TestServer, Response};
+
+    #[test]
+    fn app_only_access() {
+        let auth = Auth::new_app_only(Creds::default());
+        match PageIterator::new(&auth, &catalogue::SAVED_TRACKS, &[]) {
+            Err(Error(ErrorKind::UserRequired("SAVED_TRACKS"), _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+
+        assert!(check_access(&auth, &catalogue::ALBUMS).is_ok());
+        assert!(check_access(&Auth::new(Creds::default()), &catalogue::SAVED_TRACKS).is_ok());
+    }
+
+    #[test]
+    fn conditional_requests() {
+        use http::responsecache::ResponseCache;
+
+        let server = TestServer::start(|req| if req.path == "/api/token" {
+            Response::json(200, r#"{"access_token": "t", "expires_in": 3600}"#)
+        } else if req.header("If-None-Match") == Some("\"v1\"") {
+            Response::new(304)
+        } else {
+            Response::json(200, r#"{"albums": ["a"]}"#).header("ETag", "\"v1\"")
+        });
+        let dir = ::std::env::temp_dir().join("spotify-model-test-conditional");
+        let cache = ResponseCache::new(dir.clone());
+        cache.clear().unwrap();
+
+        let auth = Auth::new_app_only(Creds::with_secret(String::from("id"), String::from("secret")))
+            .with_endpoints(server.endpoints())
+            .with_response_cache(cache);
+        let url = auth.endpoints().api_url("albums?ids=a").unwrap();
+        for _ in 0..2 {
+            let response = send_api_request(&auth, url.clone(), &Scopes::new()).unwrap();
+            assert_eq!(response["albums"][0], "a");
+        }
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[1].header("If-None-Match"), None);
+        assert_eq!(requests[2].header("If-None-Match"), Some("\"v1\""));
+
+        // bypassed
+        let auth = auth.without_response_cache();
+        send_api_request(&auth, url, &Scopes::new()).unwrap();
+        assert_eq!(server.requests()[3].header("If-None-Match"), None);
+
+        ::std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn corrupt_cached_response() {
+        use http::responsecache::ResponseCache;
+
+        let server = TestServer::start(|req| if req.path == "/api/token" {
+            Response::json(200, r#"{"access_token": "t", "expires_in": 3600}"#)
+        } else if req.header("If-None-Match") == Some("\"v1\"") {
+            Response::new(304)
+        } else {
+            Response::json(200, r#"{"albums": ["a"]}"#).header("ETag", "\"v2\"")
+        });
+        let dir = ::std::env::temp_dir().join("spotify-model-test-corrupt-response");
+        let cache = ResponseCache::new(dir.clone());
+        cache.clear().unwrap();
+
+        let auth = Auth::new_app_only(Creds::with_secret(String::from("id"), String::from("secret")))
+            .with_endpoints(server.endpoints());
+        let url = auth.endpoints().api_url("albums?ids=a").unwrap();
+        cache.put(url.as_str(), "\"v1\"", "{not json").unwrap();
+        let auth = auth.with_response_cache(cache);
+
+        // fetched afresh instead of trusting the 304
+        let response = send_api_request(&auth, url.clone(), &Scopes::new()).unwrap();
+        assert_eq!(response["albums"][0], "a");
+        assert_eq!(server.requests()[1].header("If-None-Match"), None);
+        let cached = auth.response_cache().unwrap().get(url.as_str()).unwrap();
+        assert_eq!(cached.etag, "\"v2\"");
+
+        ::std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn rate_limited() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Instant;
+
+        let limited = AtomicUsize::new(0);
+        let server = TestServer::start(move |req| if req.path == "/api/token" {
+            Response::json(200, r#"{"access_token": "t", "expires_in": 3600}"#)
+        } else if req.path.starts_with("/v1/forever") {
+            Response::json(429, "{}").header("Retry-After", "3600")
+        } else if limited.fetch_add(1, Ordering::SeqCst) == 0 {
+            Response::json(429, "{}").header("Retry-After", "1")
+        } else {
+            Response::json(200, r#"{"ok": true}"#)
+        });
+        let auth = Auth::new_app_only(Creds::with_secret(String::from("id"), String::from("secret")))
+            .with_endpoints(server.endpoints());
+
+        // waited out, rather than failing
+        let start = Instant::now();
+        let url = auth.endpoints().api_url("albums").unwrap();
+        assert_eq!(send_api_request(&auth, url, &Scopes::new()).unwrap()["ok"], true);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(server.requests().len(), 3);
+
+        // too long to wait
+        let url = auth.endpoints().api_url("forever").unwrap();
+        match send_api_request(&auth, url, &Scopes::new()) {
+            Err(Error(ErrorKind::RateLimited(Some(3600)), _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+        assert_eq!(server.requests().len(), 4);
+    }
+
+    #[test]
+    fn flaky_server() {
+        use http::retry::RetryPolicy;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // fails in a different transient way each time, then recovers
+        let attempts = AtomicUsize::new(0);
+        let server = TestServer::start(move |req| if req.path == "/api/token" {
+            Response::json(200, r#"{"access_token": "t", "expires_in": 3600}"#)
+        } else if req.path.starts_with("/v1/missing") {
+            Response::json(404, "{}")
+        } else {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => Response::hang_up(),
+                1 => Response::json(502, "{}"),
+                2 => Response::json(503, "{}"),
+                3 => Response::json(504, "{}"),
+                _ => Response::json(200, r#"{"ok": true}"#),
+            }
+        });
+        let policy = RetryPolicy::default()
+            .with_max_attempts(5)
+            .with_delays(Duration::from_millis(1), Duration::from_millis(10));
+        let auth = Auth::new_app_only(Creds::with_secret(String::from("id"), String::from("secret")))
+            .with_endpoints(server.endpoints())
+            .with_retry_policy(policy.clone());
+
+        let url = auth.endpoints().api_url("albums").unwrap();
+        assert_eq!(send_api_request(&auth, url.clone(), &Scopes::new()).unwrap()["ok"], true);
+        assert_eq!(server.requests().len(), 6);
+
+        // client errors aren't retried
+        let missing = auth.endpoints().api_url("missing").unwrap();
+        match send_api_request(&auth, missing, &Scopes::new()) {
+            Err(Error(ErrorKind::BadResponseStatusCode(StatusCode::NotFound), _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+        assert_eq!(server.requests().len(), 7);
+
+        // nor is anything once the attempts run out
+        let server = TestServer::start(|req| if req.path == "/api/token" {
+            Response::json(200, r#"{"access_token": "t", "expires_in": 3600}"#)
+        } else {
+            Response::json(503, "{}")
+        });
+        let auth = Auth::new_app_only(Creds::with_secret(String::from("id"), String::from("secret")))
+            .with_endpoints(server.endpoints())
+            .with_retry_policy(policy.with_max_attempts(3));
+        let url = auth.endpoints().api_url("albums").unwrap();
+        match send_api_request(&auth, url, &Scopes::new()) {
+            Err(Error(ErrorKind::BadResponseStatusCode(StatusCode::ServiceUnavailable), _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+        assert_eq!(server.requests().len(), 4);
+    }
+
+    #[test]
+    fn forbidden() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/api/token" => Response::json(200, r#"{"access_token": "t", "expires_in": 3600}"#),
+            "/v1/me/player/play" => Response::json(403, r#"{"error": {"status": 403,
+                "message": "Player command failed: Premium required"}}"#),
+            _ => Response::json(403, ""),
+        });
+        let auth = Auth::new(Creds::default()).with_endpoints(server.endpoints());
+        *auth.state.write().unwrap() = Some(AuthState {
+            token: String::from("t"),
+            token_type: String::from("Bearer"),
+            expiry_time: ::time::get_time().sec + 3600,
+            refresh_token: None,
+            scopes: Scopes::parse("user-library-read"),
+            account: None,
+        });
+
+        let url = auth.endpoints().api_url("me/player/play").unwrap();
+        match send_request(&auth, Method::Put, url, &[], None, &Scopes::new()) {
+            Err(Error(ErrorKind::Forbidden(ref message, _, ref granted), _)) => {
+                assert_eq!(message, "Player command failed: Premium required");
+                assert_eq!(granted, &Scopes::parse("user-library-read"));
+            }
+            _ => assert!(false, "Error not returned"),
+        }
+
+        let url = auth.endpoints().api_url("me/tracks").unwrap();
+        match send_api_request(&auth, url, &Scopes::new()) {
+            Err(Error(ErrorKind::Forbidden(ref message, _, _), _)) => {
+                assert_eq!(message, "no reason given")
+            }
+            _ => assert!(false, "Error not returned"),
+        }
+    }
+
+    #[test]
+    fn failed_iteration() {
+        let server = TestServer::start(|req| if req.path == "/api/token" {
+            Response::json(200, r#"{"access_token": "t", "expires_in": 3600}"#)
+        } else if req.path.contains("ids=a") {
+            Response::json(200, r#"{"artists": [{"id": "a"}]}"#)
+        } else {
+            Response::json(404, "{}")
+        });
+        let auth = Auth::new_app_only(Creds::with_secret(String::from("id"), String::from("secret")))
+            .with_endpoints(server.endpoints());
+
+        // the failed batch is reported, rather than looking like the end
+        let ids: Vec<String> = (0..51).map(|i| if i < 50 { "a" } else { "b" }.to_owned()).collect();
+        let mut it = SeveralIterator::new(&auth, &catalogue::ARTISTS, &ids).unwrap();
+        assert_eq!(it.next().unwrap().unwrap()["id"], "a");
+        match it.next() {
+            Some(Err(Error(ErrorKind::BadResponseStatusCode(StatusCode::NotFound), _))) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+        assert!(it.next().is_none());
+    }
+
+    #[test]
+    fn write_requests() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let writes = AtomicUsize::new(0);
+        let server = TestServer::start(move |req| match (req.method.as_str(), req.path.as_str()) {
+            (_, "/api/token") => {
+                Response::json(200, r#"{"access_token": "t", "expires_in": 3600}"#)
+            }
+            // the first attempts at the put and the post are lost
+            _ if [0, 2].contains(&writes.fetch_add(1, Ordering::SeqCst)) => Response::hang_up(),
+            ("PUT", "/v1/me/tracks?ids=a%2Cb") => Response::new(200),
+            ("DELETE", "/v1/me/tracks?ids=a") => Response::new(204),
+            ("POST", "/v1/users/me/playlists") => Response::json(201, r#"{"id": "new"}"#),
+            ("POST", "/v1/playlists/p/tracks") => Response::json(504, "{}"),
+            _ => Response::json(400, "{}"),
+        });
+        let auth = Auth::new_app_only(Creds::with_secret(String::from("id"), String::from("secret")))
+            .with_endpoints(server.endpoints());
+        let send = |method, path, params: &[(&str, &str)], body: Option<&JsonValue>| {
+            let url = auth.endpoints().api_url(path).unwrap();
+            send_request(&auth, method, url, params, body, &Scopes::new())
+        };
+
+        // a put can safely be sent again
+        let r = send(Method::Put, "me/tracks", &[("ids", "a,b")], None);
+        assert_eq!(r.unwrap(), JsonValue::Null);
+
+        // a post can't, as it may have been received
+        let body = object!{"name" => "New"};
+        assert!(send(Method::Post, "users/me/playlists", &[], Some(&body)).is_err());
+        let r = send(Method::Post, "users/me/playlists", &[], Some(&body));
+        assert_eq!(r.unwrap()["id"], "new");
+
+        let r = send(Method::Delete, "me/tracks", &[("ids", "a")], None);
+        assert_eq!(r.unwrap(), JsonValue::Null);
+
+        // nor after a gateway timeout, as the write may have been applied behind it
+        match send(Method::Post, "playlists/p/tracks", &[], Some(&body)) {
+            Err(Error(ErrorKind::BadResponseStatusCode(StatusCode::GatewayTimeout), _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+
+        let requests = server.requests();
+        let methods: Vec<&str> = requests.iter().map(|r| r.method.as_str()).collect();
+        assert_eq!(methods, ["POST", "PUT", "PUT", "POST", "POST", "DELETE", "POST"]);
+        assert_eq!(requests[1].header("Content-Length"), Some("0"));
+        assert_eq!(requests[4].header("Content-Type"), Some("application/json"));
+        assert_eq!(requests[4].body, r#"{"name":"New"}"#);
+    }
+
+    #[test]
+    fn catalogue_endpoints() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/api/token" => Response::json(200, r#"{"access_token": "t", "expires_in": 3600}"#),
+            "/v1/albums/a1/tracks?limit=50&offset=0" => {
+                Response::json(200, r#"{"total": 1, "next": null, "items": [{"id": "t1"}]}"#)
+            }
+            "/v1/me/following?type=artist&limit=50" => {
+                let host = req.header("Host").unwrap();
+                let next = format!("http://{}/v1/me/following?after=x", host);
+                Response::json(200, &format!(r#"{{"artists": {{"total": 2, "next": "{}",
+                    "cursors": {{"after": "x"}}, "items": [{{"id": "r1"}}]}}}}"#, next))
+            }
+            "/v1/me/following?after=x" => Response::json(200, r#"{"artists": {"total": 2,
+                "next": null, "cursors": {}, "items": [{"id": "r2"}]}}"#),
+            "/v1/me/tracks?ids=t1" => Response::new(200),
+            "/v1/albums/odd/tracks?limit=50&offset=0" => Response::json(200, r#"[{"id": "t1"}]"#),
+            _ => Response::json(404, "{}"),
+        });
+        let auth = Auth::new(Creds::default()).with_endpoints(server.endpoints());
+        *auth.state.write().unwrap() = Some(AuthState {
+            token: String::from("t"),
+            token_type: String::from("Bearer"),
+            expiry_time: ::time::get_time().sec + 3600,
+            refresh_token: None,
+            scopes: Scopes::parse("user-follow-read user-library-modify"),
+            account: None,
+        });
+
+        let tracks = PageIterator::new(&auth, &catalogue::ALBUM_TRACKS, &[("id", "a1")]).unwrap();
+        let ids: Vec<_> = tracks.map(|t| t.unwrap()["id"].to_string()).collect();
+        assert_eq!(ids, ["t1"]);
+
+        // cursor pages, wrapped in an object
+        let artists = PageIterator::new(&auth, &catalogue::FOLLOWED_ARTISTS, &[]).unwrap();
+        assert_eq!(artists.total(), 2);
+        let ids: Vec<_> = artists.map(|a| a.unwrap()["id"].to_string()).collect();
+        assert_eq!(ids, ["r1", "r2"]);
+
+        // the method comes from the catalogue
+        let r = call(&auth, &catalogue::SAVE_TRACKS, &[], &[("ids", "t1")], None).unwrap();
+        assert_eq!(r, JsonValue::Null);
+        assert_eq!(server.requests().last().unwrap().method, "PUT");
+
+        match PageIterator::new(&auth, &catalogue::ALBUM_TRACKS, &[("id", "odd")]) {
+            Err(Error(ErrorKind::BadResponse(_), _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+        match PageIterator::new(&auth, &catalogue::ALBUMS, &[]) {
+            Err(Error(ErrorKind::BadEndpointUse("ALBUMS", _), _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+        match SeveralIterator::new(&auth, &catalogue::SAVED_TRACKS, &[]) {
+            Err(Error(ErrorKind::BadEndpointUse("SAVED_TRACKS", _), _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+    }
+
+    #[test]
+    fn reauth_on_unauthorized() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let issued = AtomicUsize::new(0);
+        let server = TestServer::start(move |req| if req.path == "/api/token" {
+            // the first token is revoked server-side before it expires
+            let token = match issued.fetch_add(1, Ordering::SeqCst) {
+                0 => "revoked",
+                _ => "fresh",
+            };
+            Response::json(
+                200,
+                &format!(r#"{{"access_token": "{}", "expires_in": 3600}}"#, token),
+            )
+        } else if req.header("Authorization") == Some("Bearer fresh") {
+            Response::json(200, r#"{"ok": true}"#)
+        } else {
+            Response::json(401, r#"{"error": {"status": 401}}"#)
+        });
+
+        let auth = Auth::new_app_only(Creds::with_secret(String::from("id"), String::from("secret")))
+            .with_endpoints(server.endpoints());
+
+        let response = send_api_request(&auth, auth.endpoints().api_url("albums").unwrap(), &Scopes::new());
+        assert_eq!(response.unwrap()["ok"], true);
+        assert_eq!(server.requests().len(), 4);
+
+        // only retried once
+        let server = TestServer::start(|req| if req.path == "/api/token" {
+            Response::json(200, r#"{"access_token": "revoked", "expires_in": 3600}"#)
+        } else {
+            Response::json(401, r#"{"error": {"status": 401}}"#)
+        });
+        let auth = Auth::new_app_only(Creds::with_secret(String::from("id"), String::from("secret")))
+            .with_endpoints(server.endpoints());
+        match send_api_request(&auth, auth.endpoints().api_url("albums").unwrap(), &Scopes::new()) {
+            Err(Error(ErrorKind::Unauthorized, _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+        assert_eq!(server.requests().len(), 4);
+    }
+}
@@ -0,0 +1,415 @@
+//! Keeps API responses along with their ETags, so that a request for something unchanged since
+//! the last run can be answered with `304 Not Modified` instead of the whole body again. See
+//! https://developer.spotify.com/web-api/user-guide/#conditional-requests
+//!
+//! Each response is a file in the cache directory, named after a hash of its URL. When the
+//! directory grows past its size limit, the least recently used responses are evicted. Responses
+//! can hold the user's whole library, so they're encrypted along with the token cache, with a key
+//! derived once per cache from a salt kept in the same directory
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use error::*;
+use json;
+use sha2::{Sha256, Digest};
+
+use http::cachecrypt::{self, CacheEncryption, DerivedKey};
+use http::filecache;
+
+const VERSION: u32 = 1;
+
+/// Holds the salt for encrypted entries. It isn't named like an entry, so it's never evicted
+const SALT_FILE: &str = "salt";
+
+/// When set, requests are made in full without touching the cache, such as when a cached
+/// response is suspected to be wrong
+pub const BYPASS_ENV: &str = "SPOTIFY_NO_RESPONSE_CACHE";
+
+/// Used when a profile doesn't set a limit of its own
+pub const DEFAULT_MAX_BYTES: u64 = 32 * 1024 * 1024;
+
+/// Whether `path` is a stored response, named after a hash, rather than a file that's still
+/// being written
+fn is_entry(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+#[derive(Debug)]
+pub struct ResponseCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    encryption: Option<CacheEncryption>,
+    key: Mutex<Option<DerivedKey>>,
+}
+
+/// A cached response, to be sent back as `If-None-Match`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedResponse {
+    pub etag: String,
+    pub body: String,
+}
+
+impl ResponseCache {
+    pub fn new(dir: PathBuf) -> Self {
+        ResponseCache {
+            dir: dir,
+            max_bytes: DEFAULT_MAX_BYTES,
+            encryption: None,
+            key: Mutex::new(None),
+        }
+    }
+
+    /// Evicts responses once they take up more than `max_bytes` in total
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Encrypts responses at rest. Entries stored in the clear before are ignored and removed
+    pub fn with_encryption(mut self, encryption: CacheEncryption) -> Self {
+        self.encryption = Some(encryption);
+        self.key = Mutex::new(None);
+        self
+    }
+
+    #[inline]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn entry_path(&self, url: &str) -> PathBuf {
+        let hash = Sha256::digest(url.as_bytes());
+        let name: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+        self.dir.join(name)
+    }
+
+    /// The salt that the key for every entry is derived from, created along with the first.
+    /// Entries sealed under a salt that has since been lost or replaced can't be read, so
+    /// they're ignored until evicted
+    fn salt(&self) -> SpotifyResult<Vec<u8>> {
+        let path = self.dir.join(SALT_FILE);
+        match fs::read(&path) {
+            Ok(salt) => {
+                if salt.len() == cachecrypt::SALT_LEN {
+                    return Ok(salt);
+                }
+                debug!("Replacing malformed response cache salt");
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        let salt = CacheEncryption::new_salt()?;
+        fs::create_dir_all(&self.dir)?;
+        filecache::write_private(&path, &salt)?;
+        Ok(salt)
+    }
+
+    /// The key for entries, derived on first use and then kept, since deriving it is slow
+    fn key(&self, encryption: &CacheEncryption) -> SpotifyResult<DerivedKey> {
+        let mut key = self.key.lock().unwrap();
+        if let Some(ref key) = *key {
+            return Ok(key.clone());
+        }
+        let derived = encryption.derive(&self.salt()?)?;
+        *key = Some(derived.clone());
+        Ok(derived)
+    }
+
+    /// The response last stored for `url`, if any. A corrupt entry is treated as missing
+    pub fn get(&self, url: &str) -> Option<CachedResponse> {
+        let path = self.entry_path(url);
+        let mut contents = String::new();
+        match File::open(&path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                debug!("Failed to read cached response {}: {}", path.display(), e);
+                return None;
+            }
+        }
+
+        let mut doc = json::parse(&contents).ok()?;
+        match (CacheEncryption::is_envelope(&doc), self.encryption.as_ref()) {
+            (true, Some(encryption)) => {
+                let plaintext = match self.key(encryption).and_then(|key| key.open(&doc)) {
+                    Ok(plaintext) => plaintext,
+                    Err(e) => {
+                        debug!("Failed to decrypt cached response {}: {}", path.display(), e);
+                        return None;
+                    }
+                };
+                doc = json::parse(&String::from_utf8(plaintext).ok()?).ok()?;
+            }
+            (true, None) => return None,
+            (false, Some(_)) => {
+                if let Err(e) = filecache::remove(&path) {
+                    debug!("Failed to remove unencrypted response {}: {}", path.display(), e);
+                }
+                return None;
+            }
+            (false, None) => (),
+        }
+        if doc["version"].as_u32() != Some(VERSION) || doc["url"].as_str() != Some(url) {
+            return None;
+        }
+
+        let cached = CachedResponse {
+            etag: doc["etag"].as_str()?.to_owned(),
+            body: doc["body"].as_str()?.to_owned(),
+        };
+
+        // recently used entries are the last to be evicted
+        if let Err(e) = File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|f| f.set_modified(SystemTime::now()))
+        {
+            debug!("Failed to touch cached response {}: {}", path.display(), e);
+        }
+        Some(cached)
+    }
+
+    /// Forgets the response stored for `url`, returning whether there was one
+    pub fn remove(&self, url: &str) -> SpotifyResult<bool> {
+        filecache::remove(&self.entry_path(url))
+    }
+
+    /// Stores the response for `url`, replacing any earlier one, then evicts to stay within
+    /// the size limit. A response bigger than the limit isn't stored
+    pub fn put(&self, url: &str, etag: &str, body: &str) -> SpotifyResult<()> {
+        let doc = object!{
+            "version" => VERSION,
+            "url" => url,
+            "etag" => etag,
+            "body" => body
+        };
+        let contents = match self.encryption {
+            Some(ref encryption) => self.key(encryption)?.seal(doc.dump().as_bytes())?.dump(),
+            None => doc.dump(),
+        };
+        if contents.len() as u64 > self.max_bytes {
+            debug!("Response for {} is too big to cache", url);
+            return Ok(());
+        }
+
+        fs::create_dir_all(&self.dir)?;
+        filecache::write_private(&self.entry_path(url), contents.as_bytes())?;
+
+        // the response is stored either way, and the next put will try again
+        if let Err(e) = self.evict() {
+            warn!("Failed to evict cached responses: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Removes the least recently used responses until the rest fit. Other threads may be
+    /// storing and evicting at the same time, so an entry that has since gone is taken as
+    /// already evicted
+    fn evict(&self) -> SpotifyResult<()> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if !is_entry(&path) {
+                continue;
+            }
+            let meta = match fs::metadata(&path) {
+                Ok(meta) => meta,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if meta.is_file() {
+                entries.push((meta.modified()?, meta.len(), path));
+            }
+        }
+
+        let mut total: u64 = entries.iter().map(|e| e.1).sum();
+        if total <= self.max_bytes {
+            return Ok(());
+        }
+
+        entries.sort_by_key(|e| e.0);
+        for (_, len, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            trace!("Evicting cached response {}", path.display());
+            filecache::remove(&path)?;
+            total -= len;
+        }
+        Ok(())
+    }
+
+    /// Removes every cached response, returning how many there were
+    pub fn clear(&self) -> SpotifyResult<usize> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut removed = 0;
+        for entry in entries {
+            let path = entry?.path();
+            if is_entry(&path) && filecache::remove(&path)? {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use http::responsecache::*;
+    use std::env;
+    use std::time::Duration;
+
+    fn temp_cache(name: &str) -> ResponseCache {
+        let dir = env::temp_dir().join(format!("spotify-model-test-{}", name));
+        fs::remove_dir_all(&dir).ok();
+        ResponseCache::new(dir)
+    }
+
+    #[test]
+    fn store_and_clear() {
+        let cache = temp_cache("responses");
+        assert_eq!(cache.get("http://api/v1/albums?ids=a"), None);
+        assert_eq!(cache.clear().unwrap(), 0);
+
+        cache.put("http://api/v1/albums?ids=a", "\"v1\"", r#"{"albums": []}"#).unwrap();
+        cache.put("http://api/v1/albums?ids=b", "\"v1\"", "{}").unwrap();
+        let cached = cache.get("http://api/v1/albums?ids=a").unwrap();
+        assert_eq!(cached.etag, "\"v1\"");
+        assert_eq!(cached.body, r#"{"albums": []}"#);
+
+        // replaced, and keyed by the whole url
+        cache.put("http://api/v1/albums?ids=a", "\"v2\"", "[]").unwrap();
+        assert_eq!(cache.get("http://api/v1/albums?ids=a").unwrap().etag, "\"v2\"");
+        assert_eq!(cache.get("http://api/v1/albums?ids=c"), None);
+
+        fs::write(cache.entry_path("http://corrupt"), "not json").unwrap();
+        assert_eq!(cache.get("http://corrupt"), None);
+
+        assert_eq!(cache.clear().unwrap(), 3);
+        assert_eq!(cache.get("http://api/v1/albums?ids=b"), None);
+        fs::remove_dir_all(cache.dir()).ok();
+    }
+
+    #[test]
+    fn eviction() {
+        let cache = temp_cache("responses-evict").with_max_bytes(300);
+        let body = "x".repeat(50);
+
+        cache.put("http://a", "a", &body).unwrap();
+        ::std::thread::sleep(Duration::from_millis(20));
+        cache.put("http://b", "b", &body).unwrap();
+        ::std::thread::sleep(Duration::from_millis(20));
+
+        // using a makes b the least recently used
+        assert!(cache.get("http://a").is_some());
+        ::std::thread::sleep(Duration::from_millis(20));
+        cache.put("http://c", "c", &body).unwrap();
+
+        assert!(cache.get("http://a").is_some());
+        assert_eq!(cache.get("http://b"), None);
+        assert!(cache.get("http://c").is_some());
+
+        // too big to ever fit
+        cache.put("http://d", "d", &"x".repeat(300)).unwrap();
+        assert_eq!(cache.get("http://d"), None);
+        assert!(cache.get("http://c").is_some());
+        fs::remove_dir_all(cache.dir()).ok();
+    }
+
+    #[test]
+    fn concurrent_eviction() {
+        let cache = temp_cache("responses-concurrent").with_max_bytes(2000);
+        let body = "x".repeat(100);
+        fs::create_dir_all(cache.dir()).unwrap();
+        let writing = cache.dir().join(format!("{}.tmp.1", "0".repeat(64)));
+        fs::write(&writing, "x".repeat(5000)).unwrap();
+
+        ::std::thread::scope(|s| for t in 0..4 {
+            let (cache, body) = (&cache, &body);
+            s.spawn(move || for i in 0..50 {
+                cache.put(&format!("http://{}/{}", t, i), "e", body).unwrap();
+            });
+        });
+
+        // another writer's file isn't counted or evicted
+        let total: u64 = fs::read_dir(cache.dir())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| is_entry(p))
+            .map(|p| fs::metadata(p).unwrap().len())
+            .sum();
+        assert!(total <= 2000, "{}", total);
+        assert!(writing.exists());
+        assert!(cache.get("http://3/49").is_some());
+        fs::remove_dir_all(cache.dir()).ok();
+    }
+
+    #[test]
+    fn encrypted() {
+        let passphrase = |p: &str| CacheEncryption::from_passphrase(p.to_owned());
+        let cache = temp_cache("responses-encrypted").with_encryption(passphrase("hunter2"));
+        cache.put("http://api/v1/me/tracks", "\"v1\"", r#"{"items": ["secret"]}"#).unwrap();
+
+        let path = cache.entry_path("http://api/v1/me/tracks");
+        assert!(!fs::read_to_string(&path).unwrap().contains("secret"));
+        assert_eq!(cache.get("http://api/v1/me/tracks").unwrap().etag, "\"v1\"");
+
+        // unreadable without the right key
+        let dir = cache.dir().to_owned();
+        let wrong = ResponseCache::new(dir.clone()).with_encryption(passphrase("hunter3"));
+        assert_eq!(wrong.get("http://api/v1/me/tracks"), None);
+        assert_eq!(ResponseCache::new(dir.clone()).get("http://api/v1/me/tracks"), None);
+
+        // responses cached before encryption was turned on aren't left in the clear
+        ResponseCache::new(dir).put("http://plain", "p", "{}").unwrap();
+        assert_eq!(cache.get("http://plain"), None);
+        assert!(!cache.entry_path("http://plain").exists());
+        fs::remove_dir_all(cache.dir()).ok();
+    }
+
+    #[test]
+    fn encrypted_many() {
+        use std::time::Instant;
+
+        let passphrase = CacheEncryption::from_passphrase(String::from("hunter2"));
+        let cache = temp_cache("responses-encrypted-many").with_encryption(passphrase.clone());
+
+        // one slow key derivation for the cache, rather than one per entry
+        let start = Instant::now();
+        for i in 0..200 {
+            cache.put(&format!("http://api/v1/albums/{}", i), "e", &i.to_string()).unwrap();
+        }
+        for i in 0..200 {
+            let cached = cache.get(&format!("http://api/v1/albums/{}", i)).unwrap();
+            assert_eq!(cached.body, i.to_string());
+        }
+        assert!(start.elapsed() < Duration::from_secs(10), "{:?}", start.elapsed());
+
+        // each entry has its own nonce, the salt is shared and left out of them
+        let envelope = |i: u32| {
+            let path = cache.entry_path(&format!("http://api/v1/albums/{}", i));
+            json::parse(&fs::read_to_string(path).unwrap()).unwrap()
+        };
+        assert!(envelope(0)["nonce"] != envelope(1)["nonce"]);
+        assert!(!envelope(0).has_key("salt"));
+
+        // the salt is kept, so another cache over the directory reads the same entries
+        let reopened = ResponseCache::new(cache.dir().to_owned()).with_encryption(passphrase);
+        assert_eq!(reopened.get("http://api/v1/albums/7").unwrap().body, "7");
+        assert_eq!(cache.clear().unwrap(), 200);
+        assert!(cache.dir().join(SALT_FILE).exists());
+        fs::remove_dir_all(cache.dir()).ok();
+    }
+}
@@ -0,0 +1,163 @@
+//! Decides which failed API requests are worth trying again, and how long to wait first. Only
+//! failures that are likely to pass are retried, such as a dropped connection or an overloaded
+//! server, never a request that the server has rejected as it stands
+
+use std::io;
+use std::time::Duration;
+
+use rand::{self, Rng};
+use reqwest::{self, HyperError, StatusCode};
+
+/// Gives up after this many tries, including the first
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 4;
+
+const DEFAULT_BASE_DELAY_MS: u64 = 500;
+const DEFAULT_MAX_DELAY_MS: u64 = 8000;
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    /// Randomises delays, so that clients that failed together don't retry together
+    jitter: bool,
+    statuses: Vec<StatusCode>,
+    io_errors: Vec<io::ErrorKind>,
+}
+
+impl Default for RetryPolicy {
+    /// Retries bad gateways, unavailable servers, gateway timeouts and broken connections
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            base_delay: Duration::from_millis(DEFAULT_BASE_DELAY_MS),
+            max_delay: Duration::from_millis(DEFAULT_MAX_DELAY_MS),
+            jitter: true,
+            statuses: vec![
+                StatusCode::BadGateway,
+                StatusCode::ServiceUnavailable,
+                StatusCode::GatewayTimeout,
+            ],
+            io_errors: vec![
+                io::ErrorKind::ConnectionReset,
+                io::ErrorKind::ConnectionAborted,
+                io::ErrorKind::BrokenPipe,
+                io::ErrorKind::TimedOut,
+                io::ErrorKind::UnexpectedEof,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Tries every request once only
+    pub fn never() -> Self {
+        RetryPolicy::default().with_max_attempts(1)
+    }
+
+    pub fn with_max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    /// The first retry waits `base`, doubling each time up to `max`
+    pub fn with_delays(mut self, base: Duration, max: Duration) -> Self {
+        self.base_delay = base;
+        self.max_delay = max;
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Also retries responses with this status. Client errors are never retried, as they
+    /// would only fail the same way again
+    pub fn retry_status(mut self, status: StatusCode) -> Self {
+        self.statuses.push(status);
+        self
+    }
+
+    /// Also retries requests that fail with this kind of IO error
+    pub fn retry_io_error(mut self, kind: io::ErrorKind) -> Self {
+        self.io_errors.push(kind);
+        self
+    }
+
+    #[inline]
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    pub fn retries_status(&self, status: StatusCode) -> bool {
+        !status.is_client_error() && self.statuses.contains(&status)
+    }
+
+    /// Whether the request failed in the network, in a way that might not happen again
+    pub fn retries_error(&self, error: &reqwest::Error) -> bool {
+        match error.get_ref().and_then(|e| e.downcast_ref::<HyperError>()) {
+            Some(HyperError::Io(e)) => self.io_errors.contains(&e.kind()),
+            _ => false,
+        }
+    }
+
+    /// How long to wait after `failures` failed attempts
+    pub fn delay(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(31);
+        let delay = self.base_delay
+            .checked_mul(1 << exponent)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+
+        if self.jitter {
+            // half fixed, so a retry is never immediate
+            let half = delay / 2;
+            let ms = half.as_millis() as u64;
+            half + Duration::from_millis(rand::thread_rng().gen_range(0, ms + 1))
+        } else {
+            delay
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use http::retry::*;
+
+    #[test]
+    fn rules() {
+        let policy = RetryPolicy::default();
+        assert!(policy.retries_status(StatusCode::BadGateway));
+        assert!(policy.retries_status(StatusCode::ServiceUnavailable));
+        assert!(policy.retries_status(StatusCode::GatewayTimeout));
+        assert!(!policy.retries_status(StatusCode::InternalServerError));
+        assert!(!policy.retries_status(StatusCode::NotFound));
+
+        let policy = policy
+            .retry_status(StatusCode::InternalServerError)
+            .retry_status(StatusCode::BadRequest);
+        assert!(policy.retries_status(StatusCode::InternalServerError));
+        assert!(!policy.retries_status(StatusCode::BadRequest));
+
+        assert_eq!(RetryPolicy::never().max_attempts(), 1);
+        assert_eq!(RetryPolicy::default().with_max_attempts(0).max_attempts(), 1);
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy::default()
+            .with_delays(Duration::from_millis(100), Duration::from_millis(1000))
+            .with_jitter(false);
+        let delays: Vec<_> = (1..7).map(|n| policy.delay(n).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(policy.delay(u32::MAX), Duration::from_millis(1000));
+
+        let policy = policy.with_jitter(true);
+        for n in 1..5 {
+            let delay = policy.delay(n).as_millis();
+            let full = 100 << (n - 1);
+            assert!(delay >= full / 2 && delay <= full, "{} for {}", delay, n);
+        }
+    }
+}
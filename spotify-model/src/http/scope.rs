@@ -0,0 +1,166 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// An OAuth scope, granting access to a slice of the user's data
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Scope {
+    UgcImageUpload,
+    UserReadPlaybackState,
+    UserModifyPlaybackState,
+    UserReadCurrentlyPlaying,
+    AppRemoteControl,
+    Streaming,
+    PlaylistReadPrivate,
+    PlaylistReadCollaborative,
+    PlaylistModifyPrivate,
+    PlaylistModifyPublic,
+    UserFollowModify,
+    UserFollowRead,
+    UserReadPlaybackPosition,
+    UserTopRead,
+    UserReadRecentlyPlayed,
+    UserLibraryModify,
+    UserLibraryRead,
+    UserReadEmail,
+    UserReadPrivate,
+}
+
+/// A set of scopes, formatted space-separated as in OAuth requests and responses
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Scopes(BTreeSet<Scope>);
+
+const ALL: &[(Scope, &str)] = &[
+    (Scope::UgcImageUpload, "ugc-image-upload"),
+    (Scope::UserReadPlaybackState, "user-read-playback-state"),
+    (Scope::UserModifyPlaybackState, "user-modify-playback-state"),
+    (Scope::UserReadCurrentlyPlaying, "user-read-currently-playing"),
+    (Scope::AppRemoteControl, "app-remote-control"),
+    (Scope::Streaming, "streaming"),
+    (Scope::PlaylistReadPrivate, "playlist-read-private"),
+    (Scope::PlaylistReadCollaborative, "playlist-read-collaborative"),
+    (Scope::PlaylistModifyPrivate, "playlist-modify-private"),
+    (Scope::PlaylistModifyPublic, "playlist-modify-public"),
+    (Scope::UserFollowModify, "user-follow-modify"),
+    (Scope::UserFollowRead, "user-follow-read"),
+    (Scope::UserReadPlaybackPosition, "user-read-playback-position"),
+    (Scope::UserTopRead, "user-top-read"),
+    (Scope::UserReadRecentlyPlayed, "user-read-recently-played"),
+    (Scope::UserLibraryModify, "user-library-modify"),
+    (Scope::UserLibraryRead, "user-library-read"),
+    (Scope::UserReadEmail, "user-read-email"),
+    (Scope::UserReadPrivate, "user-read-private"),
+];
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        ALL.iter().find(|s| s.0 == *self).unwrap().1
+    }
+}
+
+impl FromStr for Scope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        ALL.iter().find(|x| x.1 == s).map(|x| x.0).ok_or(())
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Scopes {
+    pub fn new() -> Self {
+        Scopes(BTreeSet::new())
+    }
+
+    /// Parses a space-separated list, skipping any scopes we don't know about
+    pub fn parse(s: &str) -> Self {
+        Scopes(
+            s.split_whitespace()
+                .filter_map(|x| match x.parse() {
+                    Ok(scope) => Some(scope),
+                    Err(_) => {
+                        warn!("Ignoring unknown scope '{}'", x);
+                        None
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    pub fn insert(&mut self, scope: Scope) {
+        self.0.insert(scope);
+    }
+
+    pub fn contains(&self, scope: Scope) -> bool {
+        self.0.contains(&scope)
+    }
+
+    pub fn contains_all(&self, other: &Scopes) -> bool {
+        self.0.is_superset(&other.0)
+    }
+
+    pub fn union(&self, other: &Scopes) -> Scopes {
+        Scopes(self.0.union(&other.0).cloned().collect())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<'a> From<&'a [Scope]> for Scopes {
+    fn from(scopes: &'a [Scope]) -> Self {
+        Scopes(scopes.iter().cloned().collect())
+    }
+}
+
+impl fmt::Display for Scopes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut first = true;
+        for scope in &self.0 {
+            if !first {
+                f.write_str(" ")?;
+            }
+            first = false;
+            f.write_str(scope.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use http::scope::*;
+
+    #[test]
+    fn scope_names() {
+        for &(scope, name) in ALL {
+            assert_eq!(scope.as_str(), name);
+            assert_eq!(name.parse::<Scope>(), Ok(scope));
+        }
+        assert_eq!("user-library-write".parse::<Scope>(), Err(()));
+    }
+
+    #[test]
+    fn scope_sets() {
+        let scopes = Scopes::parse("user-library-read  playlist-read-private nonsense");
+        assert!(scopes.contains(Scope::UserLibraryRead));
+        assert!(scopes.contains(Scope::PlaylistReadPrivate));
+        assert_eq!(scopes.to_string(), "playlist-read-private user-library-read");
+        assert_eq!(Scopes::parse(&scopes.to_string()), scopes);
+
+        let library = Scopes::from(&[Scope::UserLibraryRead][..]);
+        assert!(scopes.contains_all(&library));
+        assert!(!library.contains_all(&scopes));
+        assert!(library.contains_all(&Scopes::new()));
+        assert_eq!(library.union(&scopes), scopes);
+
+        assert!(Scopes::parse("").is_empty());
+        assert_eq!(Scopes::new().to_string(), "");
+    }
+}
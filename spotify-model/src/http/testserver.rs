@@ -0,0 +1,179 @@
+//! A tiny HTTP/1.1 server for standing in for the Spotify servers in tests
+
+use reqwest::Url;
+use http::endpoints::Endpoints;
+use http::params::Params;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+    /// Close the connection instead of responding, like a flaky network
+    hang_up: bool,
+}
+
+pub struct TestServer {
+    url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Request {
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|h| h.0.eq_ignore_ascii_case(key))
+            .map(|h| h.1.as_str())
+    }
+
+    /// Percent-decoded value of a key in the form-encoded body
+    pub fn form_value(&self, key: &str) -> Option<String> {
+        Params::parse(&self.body).get(key).map(|v| v.to_owned())
+    }
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response {
+            status: status,
+            headers: Vec::new(),
+            body: String::new(),
+            hang_up: false,
+        }
+    }
+
+    /// Closes the connection without a response
+    pub fn hang_up() -> Self {
+        Response {
+            hang_up: true,
+            ..Response::new(0)
+        }
+    }
+
+    pub fn json(status: u16, body: &str) -> Self {
+        Response::new(status)
+            .header("Content-Type", "application/json")
+            .body(body)
+    }
+
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+        self.headers.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn body(mut self, body: &str) -> Self {
+        self.body = body.to_owned();
+        self
+    }
+}
+
+impl TestServer {
+    /// Serves every connection with `handler` on a background thread until the process exits
+    pub fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&Request) -> Response + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let log = requests.clone();
+        thread::spawn(move || for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            if let Some(request) = read_request(&stream) {
+                let response = handler(&request);
+                log.lock().unwrap().push(request);
+                if !response.hang_up {
+                    write_response(stream, &response).ok();
+                }
+            }
+        });
+
+        TestServer {
+            url: url,
+            requests: requests,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Both the accounts service and the API served from here
+    pub fn endpoints(&self) -> Endpoints {
+        Endpoints::stand_in(&Url::parse(&self.url).unwrap()).unwrap()
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let (method, path) = {
+        let mut parts = line.split_whitespace();
+        (parts.next()?.to_owned(), parts.next()?.to_owned())
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(i) = line.find(':') {
+            headers.push((line[..i].to_owned(), line[i + 1..].trim().to_owned()));
+        }
+    }
+
+    let length = headers
+        .iter()
+        .find(|h| h.0.eq_ignore_ascii_case("Content-Length"))
+        .and_then(|h| h.1.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+
+    Some(Request {
+        method: method,
+        path: path,
+        headers: headers,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+fn write_response(mut stream: TcpStream, response: &Response) -> ::std::io::Result<()> {
+    write!(stream, "HTTP/1.1 {} Test\r\n", response.status)?;
+    for header in &response.headers {
+        write!(stream, "{}: {}\r\n", header.0, header.1)?;
+    }
+    write!(
+        stream,
+        "Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.body.len(),
+        response.body
+    )?;
+    stream.flush()
+}
@@ -4,8 +4,17 @@
 extern crate time;
 extern crate reqwest;
 extern crate url;
-extern crate json;
 extern crate fern;
+extern crate rand;
+extern crate sha2;
+extern crate base64;
+extern crate rpassword;
+extern crate fs2;
+extern crate argon2;
+extern crate chacha20poly1305;
+
+#[macro_use]
+extern crate json;
 
 #[macro_use]
 extern crate error_chain;
@@ -16,8 +25,16 @@ extern crate log;
 mod spotify;
 mod http;
 mod error;
+mod credentials;
+mod profile;
+mod redact;
 
 use spotify::Spotify;
+use http::auth::{AuthFlow, Creds};
+use credentials::{ProviderChain, CLIENT_CREDENTIALS, LEGACY_CREDS_ENV};
+use http::cachecrypt::CacheEncryption;
+use http::responsecache;
+use profile::Profiles;
 use error::*;
 
 fn main() {
@@ -32,6 +49,10 @@ fn main() {
             writeln!(stderr, "caused by: {}", e).expect(errmsg);
         }
 
+        if let Some(hint) = hint(e) {
+            writeln!(stderr, "hint: {}", hint).expect(errmsg);
+        }
+
         if let Some(backtrace) = e.backtrace() {
             writeln!(stderr, "backtrace: {:?}", backtrace).expect(errmsg);
         }
@@ -40,32 +61,64 @@ fn main() {
     }
 }
 
+/// The hint from the innermost error that has one, as that's closest to the real cause
+fn hint(e: &Error) -> Option<&'static str> {
+    let inner = e.1
+        .next_error
+        .as_ref()
+        .and_then(|e| e.downcast_ref::<Error>())
+        .and_then(hint);
+    inner.or_else(|| e.kind().hint())
+}
+
 fn run() -> SpotifyResult<()> {
     init_logging()?;
 
-    // temporarily read creds from environment
-    const CRED_ENV: &str = "SPOTIFY_CREDS";
-    let (user, password) = match std::env::var(CRED_ENV) {
-        Err(e) => {
-            error!("No creds provided in env var {} ({})", CRED_ENV, e);
-            std::process::exit(2);
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let profiles = Profiles::new();
+    match args.first().map(|s| s.as_str()) {
+        Some("profile") => return run_profile_command(&profiles, &args[1..]),
+        Some("logout") => {
+            // no requests are made, so neither credentials nor the cache key are needed
+            let profile = profiles.current()?;
+            Spotify::new(Creds::default(), &profile)?.logout()?;
+            info!("Logged out of profile '{}'", profile.name());
+            return Ok(());
         }
-        Ok(ref creds) => {
-            match creds.find(':') {
-                None => {
-                    error!("Creds must be in format user:password");
-                    std::process::exit(3);
-                }
-                Some(index) => {
-                    let user = (&creds[..index]).to_owned();
-                    let pass = (&creds[index + 1..]).to_owned();
-                    (user, pass)
-                }
-            }
+        Some("clear-cache") => {
+            let profile = profiles.current()?;
+            let removed = Spotify::new(Creds::default(), &profile)?.clear_response_cache()?;
+            info!("Removed {} cached responses from profile '{}'", removed, profile.name());
+            return Ok(());
         }
+        _ => (),
+    }
+
+    if std::env::var_os(LEGACY_CREDS_ENV).is_some() {
+        warn!(
+            "Ignoring {}, as passwords are no longer used. Set {} to a client id instead",
+            LEGACY_CREDS_ENV,
+            CLIENT_CREDENTIALS.env_var
+        );
+    }
+
+    // the public client id is enough to authorise with PKCE, unless overridden
+    let providers = ProviderChain::from_env(&CLIENT_CREDENTIALS)?;
+    let creds = match providers.fetch() {
+        Ok(s) => Creds::parse(&s)?,
+        Err(Error(ErrorKind::NoCredentials(_), _)) => Creds::default(),
+        Err(e) => return Err(e),
     };
 
-    let spot = Spotify::new(user, password);
+    let profile = profiles.current()?;
+    info!("Using profile '{}'", profile.name());
+    let mut spot = Spotify::new(creds, &profile)?.with_auth_flow(AuthFlow::from_env()?);
+    if let Some(encryption) = CacheEncryption::from_env()? {
+        spot = spot.with_cache_encryption(encryption);
+    }
+    if std::env::var_os(responsecache::BYPASS_ENV).is_some() {
+        spot = spot.without_response_cache();
+    }
     let items = spot.fetch_saved_tracks().chain_err(
         || "Failed to fetch saved tracks",
     )?;
@@ -78,6 +131,27 @@ fn run() -> SpotifyResult<()> {
     Ok(())
 }
 
+/// `profile list`, or `profile add|remove|switch <name>`
+fn run_profile_command(profiles: &Profiles, args: &[String]) -> SpotifyResult<()> {
+    let name = args.get(1).map(|s| s.as_str());
+    match (args.first().map(|s| s.as_str()), name) {
+        (Some("list"), None) | (None, None) => {
+            let current = profiles.current()?;
+            for name in profiles.list()? {
+                let marker = if name == current.name() { "*" } else { " " };
+                println!("{} {}", marker, name);
+            }
+        }
+        (Some("add"), Some(name)) => {
+            profiles.add(name)?;
+        }
+        (Some("remove"), Some(name)) => profiles.remove(name)?,
+        (Some("switch"), Some(name)) => profiles.switch(name)?,
+        _ => bail!("usage: profile [list | add <name> | remove <name> | switch <name>]"),
+    };
+    Ok(())
+}
+
 fn init_logging() -> SpotifyResult<()> {
     fern::Dispatch::new()
         .format(|out, message, record| {
@@ -85,7 +159,7 @@ fn init_logging() -> SpotifyResult<()> {
                 "[{}][{}] {}",
                 record.level(),
                 record.target(),
-                message
+                redact::mask(&message.to_string())
             ))
         })
         .level(log::LogLevelFilter::Error)
@@ -0,0 +1,345 @@
+//! Named profiles, each with its own token cache and settings, so that several accounts can be
+//! used from the same machine. They live under `config_dir()/profiles/<name>`
+
+use error::*;
+use spotify;
+use json::{self, JsonValue};
+use http::scope::Scopes;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Overrides the current profile for a single run
+pub const PROFILE_ENV: &str = "SPOTIFY_PROFILE";
+
+const PROFILES_DIR: &str = "profiles";
+const CURRENT_FILE: &str = "current_profile";
+const STATE_FILE: &str = "state.conf";
+const SETTINGS_FILE: &str = "settings.json";
+const RESPONSES_DIR: &str = "responses";
+
+pub struct Profiles {
+    root: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct Profile {
+    name: String,
+    dir: PathBuf,
+}
+
+/// Per-profile settings, all optional so older settings files stay readable
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ProfileSettings {
+    pub scopes: Option<Scopes>,
+    /// Seconds before expiry that tokens are renewed
+    pub renewal_margin: Option<i64>,
+    /// Overrides for the accounts and API base urls, such as a local stand-in
+    pub accounts_url: Option<String>,
+    pub api_url: Option<String>,
+    /// Bytes of API responses to keep for conditional requests, or 0 to keep none
+    pub response_cache_size: Option<u64>,
+}
+
+fn validate_name(name: &str) -> SpotifyResult<()> {
+    let valid = !name.is_empty() &&
+        name.chars().all(|c| {
+            c.is_ascii_alphanumeric() || c == '-' || c == '_'
+        });
+    if !valid {
+        bail!(ErrorKind::BadProfileName(name.to_owned()));
+    }
+    Ok(())
+}
+
+impl Profiles {
+    pub fn new() -> Self {
+        Profiles::at(spotify::config_dir())
+    }
+
+    pub fn at(root: PathBuf) -> Self {
+        Profiles { root: root }
+    }
+
+    fn profiles_dir(&self) -> PathBuf {
+        self.root.join(PROFILES_DIR)
+    }
+
+    fn profile(&self, name: &str) -> Profile {
+        Profile {
+            name: name.to_owned(),
+            dir: self.profiles_dir().join(name),
+        }
+    }
+
+    /// Names of all profiles, sorted
+    pub fn list(&self) -> SpotifyResult<Vec<String>> {
+        self.ensure_default()?;
+        let mut names = fs::read_dir(self.profiles_dir())?
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().is_dir())
+            .filter_map(|e| e.file_name().into_string().ok())
+            .collect::<Vec<String>>();
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn add(&self, name: &str) -> SpotifyResult<Profile> {
+        validate_name(name)?;
+        let profile = self.profile(name);
+        if profile.dir.exists() {
+            bail!(ErrorKind::ProfileExists(name.to_owned()));
+        }
+
+        fs::create_dir_all(&profile.dir)?;
+        Ok(profile)
+    }
+
+    /// Deletes the profile and its token cache. The default profile and the one in use can't
+    /// be removed
+    pub fn remove(&self, name: &str) -> SpotifyResult<()> {
+        let profile = self.get(name)?;
+        if name == DEFAULT_PROFILE {
+            bail!(ErrorKind::ProfileNotRemovable(name.to_owned(), "it's the default"));
+        }
+        if self.current()?.name() == name {
+            bail!(ErrorKind::ProfileNotRemovable(
+                name.to_owned(),
+                "it's in use, switch to another first",
+            ));
+        }
+
+        fs::remove_dir_all(&profile.dir)?;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> SpotifyResult<Profile> {
+        validate_name(name)?;
+        if name == DEFAULT_PROFILE {
+            self.ensure_default()?;
+        }
+
+        let profile = self.profile(name);
+        if !profile.dir.is_dir() {
+            bail!(ErrorKind::ProfileNotFound(name.to_owned()));
+        }
+        Ok(profile)
+    }
+
+    pub fn switch(&self, name: &str) -> SpotifyResult<()> {
+        self.get(name)?;
+        let mut f = File::create(self.root.join(CURRENT_FILE))?;
+        f.write_all(name.as_bytes())?;
+        Ok(())
+    }
+
+    fn current_name(&self) -> SpotifyResult<String> {
+        let mut name = String::new();
+        match File::open(self.root.join(CURRENT_FILE)) {
+            Ok(mut f) => {
+                f.read_to_string(&mut name)?;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        };
+
+        let name = name.trim();
+        Ok(if name.is_empty() {
+            DEFAULT_PROFILE.to_owned()
+        } else {
+            name.to_owned()
+        })
+    }
+
+    /// The profile last switched to, unless overridden by `SPOTIFY_PROFILE`
+    pub fn current(&self) -> SpotifyResult<Profile> {
+        match env::var(PROFILE_ENV) {
+            Ok(ref name) if !name.is_empty() => self.get(name),
+            _ => self.get(&self.current_name()?),
+        }
+    }
+
+    /// The default profile always exists, and inherits the token cache from before profiles
+    fn ensure_default(&self) -> SpotifyResult<()> {
+        let profile = self.profile(DEFAULT_PROFILE);
+        fs::create_dir_all(&profile.dir)?;
+
+        let legacy = self.root.join(STATE_FILE);
+        if legacy.is_file() && !profile.token_cache_path().exists() {
+            debug!("Moving token cache into the default profile");
+            fs::rename(legacy, profile.token_cache_path())?;
+        }
+        Ok(())
+    }
+}
+
+impl Profile {
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn dir(&self) -> &PathBuf {
+        &self.dir
+    }
+
+    pub fn token_cache_path(&self) -> PathBuf {
+        self.dir.join(STATE_FILE)
+    }
+
+    pub fn response_cache_dir(&self) -> PathBuf {
+        self.dir.join(RESPONSES_DIR)
+    }
+
+    pub fn settings(&self) -> SpotifyResult<ProfileSettings> {
+        let mut contents = String::new();
+        match File::open(self.dir.join(SETTINGS_FILE)) {
+            Ok(mut f) => {
+                f.read_to_string(&mut contents)?;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(ProfileSettings::default())
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let doc = json::parse(&contents).chain_err(|| {
+            ErrorKind::BadProfileSettings(self.name.clone())
+        })?;
+        Ok(ProfileSettings {
+            scopes: doc["scopes"].as_str().map(Scopes::parse),
+            renewal_margin: doc["renewal_margin"].as_i64(),
+            accounts_url: doc["accounts_url"].as_str().map(|s| s.to_owned()),
+            api_url: doc["api_url"].as_str().map(|s| s.to_owned()),
+            response_cache_size: doc["response_cache_size"].as_u64(),
+        })
+    }
+
+    pub fn save_settings(&self, settings: &ProfileSettings) -> SpotifyResult<()> {
+        let mut doc = JsonValue::new_object();
+        if let Some(ref scopes) = settings.scopes {
+            doc["scopes"] = scopes.to_string().into();
+        }
+        if let Some(margin) = settings.renewal_margin {
+            doc["renewal_margin"] = margin.into();
+        }
+        if let Some(ref url) = settings.accounts_url {
+            doc["accounts_url"] = url.as_str().into();
+        }
+        if let Some(ref url) = settings.api_url {
+            doc["api_url"] = url.as_str().into();
+        }
+        if let Some(size) = settings.response_cache_size {
+            doc["response_cache_size"] = size.into();
+        }
+
+        let mut f = File::create(self.dir.join(SETTINGS_FILE))?;
+        f.write_all(doc.pretty(2).as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use profile::*;
+    use http::scope::Scope;
+
+    fn temp_profiles(name: &str) -> Profiles {
+        let root = env::temp_dir().join(format!("spotify-model-test-{}", name));
+        fs::remove_dir_all(&root).ok();
+        fs::create_dir_all(&root).unwrap();
+        Profiles::at(root)
+    }
+
+    #[test]
+    fn profile_management() {
+        let profiles = temp_profiles("profiles");
+        assert_eq!(profiles.list().unwrap(), vec![DEFAULT_PROFILE]);
+        assert_eq!(profiles.current_name().unwrap(), DEFAULT_PROFILE);
+
+        let alice = profiles.add("alice").unwrap();
+        assert_eq!(alice.name(), "alice");
+        assert!(alice.token_cache_path().starts_with(alice.dir()));
+        profiles.add("bob").unwrap();
+        assert_eq!(profiles.list().unwrap(), vec!["alice", "bob", DEFAULT_PROFILE]);
+
+        match profiles.add("alice") {
+            Err(Error(ErrorKind::ProfileExists(_), _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+        for bad in &["", "../escape", "has space"] {
+            match profiles.add(bad) {
+                Err(Error(ErrorKind::BadProfileName(_), _)) => (),
+                _ => assert!(false, "Error not returned"),
+            }
+        }
+
+        profiles.switch("alice").unwrap();
+        assert_eq!(profiles.current_name().unwrap(), "alice");
+
+        for name in &["alice", DEFAULT_PROFILE] {
+            match profiles.remove(name) {
+                Err(Error(ErrorKind::ProfileNotRemovable(_, _), _)) => (),
+                _ => assert!(false, "Error not returned"),
+            }
+        }
+        profiles.switch("bob").unwrap();
+        profiles.remove("alice").unwrap();
+        assert_eq!(profiles.list().unwrap(), vec!["bob", DEFAULT_PROFILE]);
+
+        match profiles.switch("alice") {
+            Err(Error(ErrorKind::ProfileNotFound(_), _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+
+        fs::remove_dir_all(&profiles.root).ok();
+    }
+
+    #[test]
+    fn legacy_cache_moves_to_default() {
+        let profiles = temp_profiles("profiles-legacy");
+        File::create(profiles.root.join(STATE_FILE))
+            .unwrap()
+            .write_all(b"token\n1500000000")
+            .unwrap();
+
+        let default = profiles.get(DEFAULT_PROFILE).unwrap();
+        assert!(default.token_cache_path().is_file());
+        assert!(!profiles.root.join(STATE_FILE).exists());
+
+        fs::remove_dir_all(&profiles.root).ok();
+    }
+
+    #[test]
+    fn profile_settings() {
+        let profiles = temp_profiles("profiles-settings");
+        let profile = profiles.add("settings").unwrap();
+        assert_eq!(profile.settings().unwrap(), ProfileSettings::default());
+
+        let settings = ProfileSettings {
+            scopes: Some(Scopes::from(&[Scope::UserTopRead][..])),
+            renewal_margin: Some(300),
+            accounts_url: None,
+            api_url: Some(String::from("http://127.0.0.1:8080/v1/")),
+            response_cache_size: Some(0),
+        };
+        profile.save_settings(&settings).unwrap();
+        assert_eq!(profile.settings().unwrap(), settings);
+
+        File::create(profile.dir().join(SETTINGS_FILE))
+            .unwrap()
+            .write_all(b"{ nope")
+            .unwrap();
+        match profile.settings() {
+            Err(Error(ErrorKind::BadProfileSettings(_), _)) => (),
+            _ => assert!(false, "Error not returned"),
+        }
+
+        fs::remove_dir_all(&profiles.root).ok();
+    }
+}
@@ -0,0 +1,197 @@
+//! Keeps secrets out of debug output and logs, so that trace logs are safe to attach to bug
+//! reports
+
+use std::fmt;
+
+const MASK: &str = "<redacted>";
+
+/// Keys whose values are masked wherever they appear as `key=value` or `"key": "value"`, as
+/// well as `Cookie: value` headers
+const SECRET_KEYS: &[&str] = &[
+    "access_token",
+    "refresh_token",
+    "token",
+    "code",
+    "code_verifier",
+    "client_secret",
+    "password",
+    "passphrase",
+    "sp_dc",
+    "sp_ac",
+    "sp_key",
+    "cookie",
+    "set-cookie",
+];
+
+/// Authorization schemes, whose credentials follow a space
+const SCHEMES: &[&str] = &["Bearer", "Basic"];
+
+/// Shows that a value is present without showing what it is
+pub struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(MASK)
+    }
+}
+
+impl fmt::Display for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(MASK)
+    }
+}
+
+/// For use in `Debug` implementations of secret-holding fields
+pub fn redacted<T>(secret: &Option<T>) -> Option<Redacted> {
+    secret.as_ref().map(|_| Redacted)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+/// Where a value is about to start, if `s` begins with a separator after `key`. A colon only
+/// counts after a json key or a header, so prose such as `token: expired` is left alone
+fn value_start(s: &str, key: &str) -> Option<usize> {
+    let mut chars = s.char_indices().peekable();
+
+    // the closing quote of a json key
+    let quoted = if let Some(&(_, '"')) = chars.peek() {
+        chars.next();
+        true
+    } else {
+        false
+    };
+
+    match chars.next() {
+        Some((_, '=')) => (),
+        Some((_, ':')) if quoted || key.ends_with("cookie") => (),
+        _ => return None,
+    }
+
+    while let Some(&(i, c)) = chars.peek() {
+        match c {
+            ' ' | '"' => {
+                chars.next();
+            }
+            _ => return Some(i),
+        }
+    }
+    Some(s.len())
+}
+
+/// The length of the value at the start of `s`. Cookie headers run to the end of the line
+fn value_len(s: &str, key: &str) -> usize {
+    let ends_at = |c: char| if key.ends_with("cookie") {
+        c == '\n' || c == '"'
+    } else {
+        c.is_whitespace() || "&;,\"'}])".contains(c)
+    };
+    s.find(ends_at).unwrap_or(s.len())
+}
+
+/// Replaces the values of secret keys and authorization credentials in `message` with a mask
+pub fn mask(message: &str) -> String {
+    let mut out = String::with_capacity(message.len());
+    let mut rest = message;
+    let mut prev: Option<char> = None;
+
+    'scan: while let Some(c) = rest.chars().next() {
+        if prev.map(|p| !is_word_char(p)).unwrap_or(true) {
+            let lower = rest.get(..rest.len().min(16)).unwrap_or(rest).to_ascii_lowercase();
+
+            for key in SECRET_KEYS {
+                if !lower.starts_with(key) ||
+                    rest[key.len()..].chars().next().map(is_word_char) == Some(true)
+                {
+                    continue;
+                }
+
+                if let Some(start) = value_start(&rest[key.len()..], key) {
+                    let start = key.len() + start;
+                    let len = value_len(&rest[start..], key);
+                    if len > 0 {
+                        out.push_str(&rest[..start]);
+                        out.push_str(MASK);
+                        rest = &rest[start + len..];
+                        prev = Some('>');
+                        continue 'scan;
+                    }
+                }
+            }
+
+            for scheme in SCHEMES {
+                if rest.starts_with(scheme) && rest[scheme.len()..].starts_with(' ') {
+                    let start = scheme.len() + 1;
+                    let len = value_len(&rest[start..], scheme);
+                    if len > 0 {
+                        out.push_str(&rest[..start]);
+                        out.push_str(MASK);
+                        rest = &rest[start + len..];
+                        prev = Some('>');
+                        continue 'scan;
+                    }
+                }
+            }
+        }
+
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+        prev = Some(c);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use redact::*;
+
+    #[test]
+    fn masked_values() {
+        let cases = [
+            ("access_token=abc&expires_in=3600", "access_token=<redacted>&expires_in=3600"),
+            ("?code=abc&state=xyz", "?code=<redacted>&state=xyz"),
+            (
+                r#"{"access_token": "abc", "scope": "x"}"#,
+                r#"{"access_token": "<redacted>", "scope": "x"}"#,
+            ),
+            ("Authorization: Bearer abc.def", "Authorization: Bearer <redacted>"),
+            ("Basic aWQ6c2VjcmV0", "Basic <redacted>"),
+            ("Cookie: sp_dc=abc; sp_t=1", "Cookie: <redacted>"),
+            ("Set-Cookie: sp_dc=abc; Path=/", "Set-Cookie: <redacted>"),
+            ("TOKEN=abc", "TOKEN=<redacted>"),
+            ("password=hunter2 and more", "password=<redacted> and more"),
+            ("sp_dc=abc; Path=/", "sp_dc=<redacted>; Path=/"),
+        ];
+        for &(message, expected) in &cases {
+            assert_eq!(mask(message), expected);
+        }
+    }
+
+    #[test]
+    fn untouched_messages() {
+        let cases = [
+            "Loaded token from file successfully",
+            "Refreshing access token",
+            "Failed to refresh token: invalid_grant",
+            "token_type=Bearer",
+            "encoded=abc&decode=1",
+            "expires_in=3600",
+            "code=",
+            "Bearer",
+            "unicode \u{1f3b5} is fine",
+            "",
+        ];
+        for message in &cases {
+            assert_eq!(mask(message), *message);
+        }
+    }
+
+    #[test]
+    fn redacted_debug() {
+        assert_eq!(format!("{:?}", redacted(&Some("secret"))), "Some(<redacted>)");
+        assert_eq!(format!("{:?}", redacted::<String>(&None)), "None");
+        assert_eq!(Redacted.to_string(), MASK);
+    }
+}
@@ -1,4 +1,5 @@
 use reqwest::Url;
+use time::Duration;
 use error::*;
 use json::JsonValue;
 
@@ -6,8 +7,17 @@ use std::env;
 use std::path::PathBuf;
 use std::fs;
 use std::collections::HashSet;
-
-use http::auth::Auth;
+use std::thread;
+
+use http::auth::{Auth, AuthFlow, Creds};
+use http::cachecrypt::CacheEncryption;
+use http::endpoints::Endpoints;
+use http::catalogue;
+use http::events::AuthObserver;
+use http::ratelimit::RateLimiter;
+use http::retry::RetryPolicy;
+use http::responsecache::{ResponseCache, DEFAULT_MAX_BYTES};
+use profile::Profile;
 use http::request::*;
 
 pub struct Spotify {
@@ -21,80 +31,190 @@ pub struct SavedItems {
 }
 
 impl Spotify {
-    pub fn new(username: String, password: String) -> Self {
-        let auth = Auth::new(username, password);
+    /// Authorises as the profile's user, caching the token in the profile's directory
+    pub fn new(creds: Creds, profile: &Profile) -> SpotifyResult<Self> {
+        let settings = profile.settings()?;
+        let endpoints = Endpoints::parse(
+            settings.accounts_url.as_deref(),
+            settings.api_url.as_deref(),
+        )?;
+        let mut auth = Auth::new(creds)
+            .with_cache_path(profile.token_cache_path())
+            .with_endpoints(endpoints);
+        if let Some(scopes) = settings.scopes {
+            auth = auth.with_scopes(scopes);
+        }
+        if let Some(margin) = settings.renewal_margin {
+            auth = auth.with_renewal_margin(Duration::seconds(margin));
+        }
+        match settings.response_cache_size {
+            Some(0) => (),
+            size => {
+                let cache = ResponseCache::new(profile.response_cache_dir())
+                    .with_max_bytes(size.unwrap_or(DEFAULT_MAX_BYTES));
+                auth = auth.with_response_cache(cache);
+            }
+        }
+
+        Ok(Spotify { auth: auth })
+    }
+
+    /// Without a user, so only the public catalog can be read
+    pub fn new_app_only(creds: Creds) -> Self {
+        let auth = Auth::new_app_only(creds);
 
         Spotify { auth: auth }
     }
 
+    pub fn with_cache_encryption(self, encryption: CacheEncryption) -> Self {
+        Spotify { auth: self.auth.with_cache_encryption(encryption) }
+    }
+
+    pub fn with_auth_flow(self, flow: AuthFlow) -> Self {
+        Spotify { auth: self.auth.with_flow(flow) }
+    }
+
+    /// Told of changes in the auth state, such as to show a login prompt
+    pub fn with_auth_observer<O: AuthObserver + 'static>(self, observer: O) -> Self {
+        Spotify { auth: self.auth.with_observer(observer) }
+    }
+
+    /// Talks to somewhere other than spotify.com, overriding the profile's settings
+    pub fn with_endpoints(self, endpoints: Endpoints) -> Self {
+        Spotify { auth: self.auth.with_endpoints(endpoints) }
+    }
+
+    /// Spaces out this instance's requests differently, such as for a stricter quota
+    pub fn with_rate_limiter(self, limiter: RateLimiter) -> Self {
+        Spotify { auth: self.auth.with_rate_limiter(limiter) }
+    }
+
+    /// Changes which failed requests are tried again, and how long to wait between tries
+    pub fn with_retry_policy(self, policy: RetryPolicy) -> Self {
+        Spotify { auth: self.auth.with_retry_policy(policy) }
+    }
+
+    /// Makes every request in full, ignoring and leaving alone any cached responses
+    pub fn without_response_cache(self) -> Self {
+        Spotify { auth: self.auth.without_response_cache() }
+    }
+
+    /// Removes the profile's cached responses, returning how many there were
+    pub fn clear_response_cache(&self) -> SpotifyResult<usize> {
+        match self.auth.response_cache() {
+            Some(cache) => cache.clear(),
+            None => Ok(0),
+        }
+    }
+
+    /// Forgets the profile's token and the responses fetched with it, so the next run
+    /// authorises again
+    pub fn logout(&self) -> SpotifyResult<()> {
+        self.auth.logout()?;
+        self.clear_response_cache()?;
+        Ok(())
+    }
+
     pub fn fetch_saved_tracks(&self) -> SpotifyResult<SavedItems> {
         let mut album_ids = HashSet::<String>::new();
         let mut artist_ids = HashSet::new();
 
-        let tracks = PageIterator::new(&self.auth, ApiEndpoint::SavedTracks)?
-            .map(|mut o| {
-                let mut track = o["track"].take();
-
-                let album = track["album"]["id"].take_string().unwrap();
-                let artists = collect_artist_ids(track["artists"].take());
-
-                album_ids.insert(album.clone());
-                artist_ids.extend(artists.clone());
-
-                Track {
-                    album_id: album,
-                    artist_ids: artists,
-                    disc: track["disc_number"].as_u8().unwrap(),
-                    track_no: track["track_number"].as_u16().unwrap(),
-                    duration_ms: track["duration_ms"].as_u32().unwrap(),
-                    name: track["name"].take_string().unwrap(),
-                }
-            })
-            .collect::<Vec<Track>>();
-
-        let ids = album_ids.into_iter().collect::<Vec<String>>();
-        let albums = SeveralIterator::new(&self.auth, ApiEndpoint::Albums, &ids)?
-            .map(|mut o| {
-                // TODO parse out of strings
-                let release_date = SpotifyDate::from(
-                    o["release_date"].take_string().unwrap(),
-                    o["release_date_precision"].take_string().unwrap(),
-                );
-
-                Album {
-                    album_id: o["id"].take_string().unwrap(),
-                    artist_ids: collect_artist_ids(o["artists"].take()),
-                    images: collect_images(o["images"].take()),
-                    release_date: release_date,
-                    name: o["name"].take_string().unwrap(),
-                }
-            })
-            .collect();
-
-        let ids = artist_ids.into_iter().collect::<Vec<String>>();
-        let artists = SeveralIterator::new(&self.auth, ApiEndpoint::Artists, &ids)?
-            .map(|mut o| {
-                let genres = o["genres"]
-                    .members_mut()
-                    .map(|g| g.take_string().unwrap())
-                    .collect();
-
-                Artist {
-                    artist_id: o["id"].take_string().unwrap(),
-                    images: collect_images(o["images"].take()),
-                    genres: genres,
-                    name: o["name"].take_string().unwrap(),
-                }
-            })
-            .collect();
+        let pages = PageIterator::new(&self.auth, &catalogue::SAVED_TRACKS, &[])?;
+        let total = pages.total() as usize;
+        let tracks = collect_all(pages, "saved tracks", total, |mut o| {
+            let mut track = o["track"].take();
 
+            let album = track["album"]["id"].take_string().unwrap();
+            let artists = collect_artist_ids(track["artists"].take());
+
+            album_ids.insert(album.clone());
+            artist_ids.extend(artists.clone());
+
+            Track {
+                album_id: album,
+                artist_ids: artists,
+                disc: track["disc_number"].as_u8().unwrap(),
+                track_no: track["track_number"].as_u16().unwrap(),
+                duration_ms: track["duration_ms"].as_u32().unwrap(),
+                name: track["name"].take_string().unwrap(),
+            }
+        })?;
+
+        // the token is shared, so both lookups can run at once
+        let album_ids = album_ids.into_iter().collect::<Vec<String>>();
+        let artist_ids = artist_ids.into_iter().collect::<Vec<String>>();
+        let (albums, artists) = thread::scope(|scope| {
+            let albums = scope.spawn(|| self.fetch_albums(&album_ids));
+            let artists = self.fetch_artists(&artist_ids);
+            (albums.join().expect("album fetching panicked"), artists)
+        });
 
         Ok(SavedItems {
             tracks: tracks,
-            albums: albums,
-            artists: artists,
+            albums: albums?,
+            artists: artists?,
         })
     }
+
+    fn fetch_albums(&self, ids: &[String]) -> SpotifyResult<Vec<Album>> {
+        let albums = SeveralIterator::new(&self.auth, &catalogue::ALBUMS, ids)?;
+        collect_all(albums, "albums", ids.len(), |mut o| {
+            // TODO parse out of strings
+            let release_date = SpotifyDate::from(
+                o["release_date"].take_string().unwrap(),
+                o["release_date_precision"].take_string().unwrap(),
+            );
+
+            Album {
+                album_id: o["id"].take_string().unwrap(),
+                artist_ids: collect_artist_ids(o["artists"].take()),
+                images: collect_images(o["images"].take()),
+                release_date: release_date,
+                name: o["name"].take_string().unwrap(),
+            }
+        })
+    }
+
+    fn fetch_artists(&self, ids: &[String]) -> SpotifyResult<Vec<Artist>> {
+        let artists = SeveralIterator::new(&self.auth, &catalogue::ARTISTS, ids)?;
+        collect_all(artists, "artists", ids.len(), |mut o| {
+            let genres = o["genres"]
+                .members_mut()
+                .map(|g| g.take_string().unwrap())
+                .collect();
+
+            Artist {
+                artist_id: o["id"].take_string().unwrap(),
+                images: collect_images(o["images"].take()),
+                genres: genres,
+                name: o["name"].take_string().unwrap(),
+            }
+        })
+    }
+}
+
+/// Converts every item, or fails saying how many were fetched before the error
+fn collect_all<I, T, F>(
+    items: I,
+    what: &'static str,
+    expected: usize,
+    mut f: F,
+) -> SpotifyResult<Vec<T>>
+where
+    I: Iterator<Item = SpotifyResult<JsonValue>>,
+    F: FnMut(JsonValue) -> T,
+{
+    let mut collected = Vec::with_capacity(expected);
+    for item in items {
+        match item {
+            Ok(o) => collected.push(f(o)),
+            Err(e) => {
+                let fetched = collected.len();
+                return Err(e).chain_err(|| ErrorKind::PartialResult(what, fetched, expected));
+            }
+        }
+    }
+    Ok(collected)
 }
 
 type SpotifyId = String;
@@ -185,6 +305,90 @@ mod test {
     use json;
     use spotify::*;
     use reqwest::Url;
+    use profile::Profiles;
+    use http::testserver::{TestServer, Response};
+    use std::io::Write;
+
+    /// A profile with a valid cached token, so nothing needs a browser
+    fn offline_profile(name: &str) -> (PathBuf, Profile) {
+        let root = env::temp_dir().join(format!("spotify-model-test-{}", name));
+        fs::remove_dir_all(&root).ok();
+        let profile = Profiles::at(root.clone()).add(name).unwrap();
+        fs::File::create(profile.token_cache_path())
+            .unwrap()
+            .write_all(
+                object!{
+                    "version" => 1,
+                    "token" => "cached",
+                    "token_type" => "Bearer",
+                    "expiry_time" => ::time::get_time().sec + 3600,
+                    "scopes" => "user-library-read"
+                }.dump()
+                    .as_bytes(),
+            )
+            .unwrap();
+        (root, profile)
+    }
+
+    #[test]
+    fn offline_saved_tracks() {
+        let server = TestServer::start(|req| if req.header("Authorization") !=
+            Some("Bearer cached")
+        {
+            Response::json(401, r#"{"error": {"status": 401}}"#)
+        } else if req.path.starts_with("/v1/me/tracks") {
+            Response::json(200, r#"{"total": 1, "next": null, "items": [{"track": {
+                "album": {"id": "album1"}, "artists": [{"id": "artist1"}], "disc_number": 1,
+                "track_number": 2, "duration_ms": 180000, "name": "Track"}}]}"#)
+        } else if req.path.starts_with("/v1/albums") {
+            Response::json(200, r#"{"albums": [{"id": "album1", "release_date": "2017",
+                "release_date_precision": "year", "artists": [{"id": "artist1"}],
+                "images": [], "name": "Album"}]}"#)
+        } else if req.path.starts_with("/v1/artists") {
+            Response::json(200, r#"{"artists": [{"id": "artist1", "images": [],
+                "genres": ["fun"], "name": "Artist"}]}"#)
+        } else {
+            Response::new(404)
+        });
+
+        let (root, profile) = offline_profile("offline");
+        let spot = Spotify::new(Creds::default(), &profile)
+            .unwrap()
+            .with_endpoints(server.endpoints());
+        let items = spot.fetch_saved_tracks().unwrap();
+        assert_eq!(items.tracks.len(), 1);
+        assert_eq!(items.tracks[0].name, "Track");
+        assert_eq!(items.albums[0].name, "Album");
+        assert_eq!(items.artists[0].genres, vec!["fun"]);
+        assert!(server.requests().iter().any(|r| r.path.contains("ids=artist1")));
+
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn partial_saved_tracks() {
+        // the second page is gone by the time it's asked for
+        let server = TestServer::start(|req| if req.path.contains("offset=0") {
+            let next = format!("http://{}/v1/me/tracks?offset=1", req.header("Host").unwrap());
+            Response::json(200, &format!(r#"{{"total": 2, "next": "{}", "items": [{{"track": {{
+                "album": {{"id": "album1"}}, "artists": [], "disc_number": 1,
+                "track_number": 1, "duration_ms": 1000, "name": "Track"}}}}]}}"#, next))
+        } else {
+            Response::new(404)
+        });
+
+        let (root, profile) = offline_profile("partial");
+        let spot = Spotify::new(Creds::default(), &profile)
+            .unwrap()
+            .with_endpoints(server.endpoints());
+        match spot.fetch_saved_tracks() {
+            Err(Error(ErrorKind::PartialResult("saved tracks", 1, 2), _)) => (),
+            r => assert!(false, "Error not returned: {:?}", r.map(|i| i.tracks.len())),
+        }
+        assert_eq!(server.requests().len(), 2);
+
+        fs::remove_dir_all(&root).ok();
+    }
 
     #[test]
     fn artist_collection() {